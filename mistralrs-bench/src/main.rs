@@ -76,6 +76,8 @@ fn run_bench(
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        timeout_ms: None,
+        user: None,
     });
 
     let mut usages = Vec::new();
@@ -98,6 +100,9 @@ fn run_bench(
                     Response::ValidationError(e) => {
                         unreachable!("Got a validation error: {e:?}");
                     }
+                    Response::TimeoutError(e) => {
+                        unreachable!("Got a timeout error: {e:?}");
+                    }
                     Response::Done(res) => {
                         usages.push(res.usage);
                     }
@@ -238,6 +243,8 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        timeout_ms: None,
+        user: None,
     });
 
     sender
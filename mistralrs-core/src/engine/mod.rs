@@ -29,7 +29,7 @@ use crate::{
     sampler::Sampler,
     scheduler::{Scheduler, SchedulerMethod},
     sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
-    Constraint, StopTokens,
+    Constraint, JsonSchemaConstraint, StopTokens,
 };
 
 const SEED: u64 = 0;
@@ -248,14 +248,19 @@ impl Engine {
                 SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx)?)?.into())
             }
             Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
+            Constraint::JsonSchema(schema) => {
+                let constraint = JsonSchemaConstraint::new(schema.clone())?;
+                SequenceRecognizer::Regex(
+                    StackRecognizer::from(RecRx::from_rx(constraint.regex())?)?.into(),
+                )
+            }
             Constraint::None => SequenceRecognizer::None,
         };
         Ok(recognizer)
     }
 
     fn alloc_logits_bias(&self, logits_bias: Option<HashMap<u32, f32>>) -> Result<Option<Tensor>> {
-        let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
-        let vocab_size = tokenizer.get_vocab_size(true);
+        let vocab_size = get_mut_arcmutex!(self.pipeline).get_vocab_size();
 
         match logits_bias {
             Some(bias) => {
@@ -362,6 +367,7 @@ impl Engine {
             return;
         }
 
+        let mut input_tokens_truncated = 0;
         if prompt.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
             if !self.truncate_sequence {
                 request
@@ -384,6 +390,7 @@ impl Engine {
                     10
                 };
                 prompt = prompt[(currently_over + sampling_max)..].to_vec();
+                input_tokens_truncated = prompt_len - prompt.len();
                 warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt.len());
             }
         }
@@ -456,12 +463,21 @@ impl Engine {
             }
         };
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+        let mut group = SequenceGroup::new(
             request.sampling_params.n_choices,
             request.is_streaming,
             is_chat,
             best_of,
-        )));
+        );
+        group.input_tokens_truncated = input_tokens_truncated;
+        if let Some(timeout_ms) = request.timeout_ms {
+            group = group.with_timeout(timeout_ms);
+        }
+        if let Some(user) = &request.user {
+            group.attach_user_context(serde_json::Value::String(user.clone()));
+        }
+        let group_signals = group.signals();
+        let group = Arc::new(tokio::sync::Mutex::new(group));
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time travel has occurred!");
@@ -519,7 +535,7 @@ impl Engine {
                 }
             };
 
-            let seq = Sequence::new_waiting(
+            let mut seq = Sequence::new_waiting(
                 prompt.clone(),
                 self.id,
                 now.as_millis(),
@@ -549,6 +565,7 @@ impl Engine {
                 request.adapters.clone(),
                 images.clone(),
             );
+            seq.join_group_signals(group_signals.clone());
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 seq.prefill(
                     prefill_cache.normal,
@@ -561,5 +578,6 @@ impl Engine {
             self.id += 1;
             self.scheduler.add_seq(seq);
         }
+        group.lock().await.start_timeout(request.response.clone());
     }
 }
@@ -0,0 +1,163 @@
+//! Compile a JSON schema into a regex for constrained generation.
+//!
+//! A subset of JSON schema is supported: the `string`, `integer`, `number`, `boolean` and `null` types,
+//! arrays with `items`, objects with `properties`, `enum`, `const`, `anyOf`/`oneOf` and lists of types.
+//! Objects are generated with every property, in the order serde_json iterates them, so the output is always
+//! valid against the schema even when some properties are optional. Anything else, including `$ref`, is rejected.
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+const WS: &str = r"[ \t\n]*";
+const STRING: &str = r#""([^"\\\x00-\x1f]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})*""#;
+const INTEGER: &str = r"-?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+
+/// A JSON schema compiled into a regex which only matches JSON values valid against it.
+#[derive(Clone, Debug)]
+pub struct JsonSchemaConstraint {
+    schema: Value,
+    regex: String,
+}
+
+impl JsonSchemaConstraint {
+    pub fn new(schema: Value) -> Result<Self> {
+        let regex = schema_to_regex(&schema)?;
+        Ok(Self { schema, regex })
+    }
+
+    pub fn schema(&self) -> &Value {
+        &self.schema
+    }
+
+    /// The regex, in the dialect accepted by the regex constraint.
+    pub fn regex(&self) -> &str {
+        &self.regex
+    }
+}
+
+fn escape(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn alternation(options: impl IntoIterator<Item = String>) -> String {
+    format!("({})", options.into_iter().collect::<Vec<_>>().join("|"))
+}
+
+fn schema_to_regex(schema: &Value) -> Result<String> {
+    let Value::Object(schema) = schema else {
+        bail!("Expected a JSON schema object, got `{schema}`.");
+    };
+    if let Some(value) = schema.get("const") {
+        return Ok(escape(&value.to_string()));
+    }
+    if let Some(values) = schema.get("enum") {
+        let Value::Array(values) = values else {
+            bail!("`enum` must be an array.");
+        };
+        return Ok(alternation(values.iter().map(|v| escape(&v.to_string()))));
+    }
+    if let Some(options) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+        let Value::Array(options) = options else {
+            bail!("`anyOf` and `oneOf` must be arrays.");
+        };
+        return Ok(alternation(
+            options
+                .iter()
+                .map(schema_to_regex)
+                .collect::<Result<Vec<_>>>()?,
+        ));
+    }
+    match schema.get("type") {
+        Some(Value::String(ty)) => type_to_regex(ty, schema),
+        Some(Value::Array(types)) => Ok(alternation(
+            types
+                .iter()
+                .map(|ty| match ty {
+                    Value::String(ty) => type_to_regex(ty, schema),
+                    _ => bail!("Expected a type name, got `{ty}`."),
+                })
+                .collect::<Result<Vec<_>>>()?,
+        )),
+        Some(ty) => bail!("Expected a type name, got `{ty}`."),
+        None => {
+            bail!("Unsupported JSON schema, it has no `type`, `const`, `enum`, `anyOf` or `oneOf`.")
+        }
+    }
+}
+
+fn type_to_regex(ty: &str, schema: &serde_json::Map<String, Value>) -> Result<String> {
+    match ty {
+        "string" => Ok(STRING.to_string()),
+        "integer" => Ok(INTEGER.to_string()),
+        "number" => Ok(NUMBER.to_string()),
+        "boolean" => Ok("(true|false)".to_string()),
+        "null" => Ok("null".to_string()),
+        "array" => {
+            let Some(items) = schema.get("items") else {
+                bail!("Arrays must specify `items`.");
+            };
+            let item = schema_to_regex(items)?;
+            Ok(format!(r"\[{WS}({item}({WS},{WS}{item})*)?{WS}\]"))
+        }
+        "object" => {
+            let Some(Value::Object(properties)) = schema.get("properties") else {
+                bail!("Objects must specify `properties`.");
+            };
+            let properties = properties
+                .iter()
+                .map(|(name, property)| {
+                    let name = escape(&Value::String(name.clone()).to_string());
+                    Ok(format!("{name}{WS}:{WS}{}", schema_to_regex(property)?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(format!(
+                r"\{{{WS}{}{WS}\}}",
+                properties.join(&format!("{WS},{WS}"))
+            ))
+        }
+        _ => bail!("Unsupported JSON schema type `{ty}`."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex_automata::meta::Regex;
+    use serde_json::json;
+
+    use super::JsonSchemaConstraint;
+
+    fn matcher(schema: serde_json::Value) -> Regex {
+        let constraint = JsonSchemaConstraint::new(schema).unwrap();
+        Regex::new(&format!("^({})$", constraint.regex())).unwrap()
+    }
+
+    #[test]
+    fn test_object() {
+        let rx = matcher(json!({
+            "type": "object",
+            "properties": {
+                "age": {"type": "integer"},
+                "name": {"type": "string"},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}}
+            }
+        }));
+        assert!(rx.is_match(r#"{"age": 3, "name": "x\"y", "tags": ["a", "b"]}"#));
+        assert!(rx.is_match(r#"{"age":-10,"name":"","tags":[]}"#));
+        assert!(!rx.is_match(r#"{"age": 3.5, "name": "x", "tags": []}"#));
+        assert!(!rx.is_match(r#"{"age": 3, "name": "x", "tags": ["c"]}"#));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert!(JsonSchemaConstraint::new(json!({"$ref": "#/foo"})).is_err());
+        assert!(JsonSchemaConstraint::new(json!({"type": "array"})).is_err());
+    }
+}
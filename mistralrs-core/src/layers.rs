@@ -515,6 +515,10 @@ impl QLinear {
         &mut self.inner
     }
 
+    pub fn inner_ref(&self) -> &QMatMul {
+        &self.inner
+    }
+
     pub fn is_quant(&self) -> bool {
         matches!(self.inner, QMatMul::QTensor(_))
     }
@@ -548,6 +552,82 @@ impl Module for QLinear {
 
 mod tests {
 
+    #[test]
+    fn padded_attention_matches_unpadded() {
+        use candle_core::{DType, Device, Tensor};
+
+        use crate::layers::{CausalMasker, ScaledDotProductAttention};
+
+        const N_HEADS: usize = 2;
+        const HEAD_DIM: usize = 4;
+        const N_PAD: usize = 2;
+        const LEN: usize = 3;
+
+        let dev = Device::Cpu;
+        let randn = |len| Tensor::randn(0f32, 1., (1, N_HEADS, len, HEAD_DIM), &dev).unwrap();
+        let pad = |xs: &Tensor| Tensor::cat(&[&randn(N_PAD), xs], 2).unwrap();
+        let attend = |q: &Tensor,
+                      k: &Tensor,
+                      v: &Tensor,
+                      cache: &[Option<(Tensor, Tensor)>],
+                      padding_mask: Option<&Tensor>| {
+            let (_, _, q_len, _) = q.dims4().unwrap();
+            let input_ids = Tensor::zeros((1, q_len), DType::U32, &dev).unwrap();
+            let bias = CausalMasker
+                .make_causal_mask_with_padding_as_attn_bias(
+                    &input_ids,
+                    cache,
+                    padding_mask,
+                    DType::F32,
+                    N_HEADS,
+                )
+                .unwrap();
+            ScaledDotProductAttention
+                .run_attention(q, k, v, N_HEADS, HEAD_DIM, bias.as_ref(), false, 1, q_len)
+                .unwrap()
+        };
+        let max_diff = |a: &Tensor, b: &Tensor| {
+            (a - b)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap()
+        };
+
+        // Prompt: the padded sequence attends the same real keys as the unpadded one.
+        let (q, k, v) = (randn(LEN), randn(LEN), randn(LEN));
+        let (k_padded, v_padded) = (pad(&k), pad(&v));
+        let prompt_mask = Tensor::new(&[[0u8, 0, 1, 1, 1]], &dev).unwrap();
+        let unpadded = attend(&q, &k, &v, &[None], None);
+        let padded = attend(&pad(&q), &k_padded, &v_padded, &[None], Some(&prompt_mask));
+        assert!(max_diff(&padded.narrow(2, N_PAD, LEN).unwrap(), &unpadded) < 1e-5);
+
+        // Decoding one token: the padding stays masked in the cache.
+        let (q_new, k_new, v_new) = (randn(1), randn(1), randn(1));
+        let cat = |cache: &Tensor, new: &Tensor| Tensor::cat(&[cache, new], 2).unwrap();
+        let unpadded = attend(
+            &q_new,
+            &cat(&k, &k_new),
+            &cat(&v, &v_new),
+            &[Some((k.clone(), v.clone()))],
+            None,
+        );
+        let decode_mask = Tensor::new(&[[0u8, 0, 1, 1, 1, 1]], &dev).unwrap();
+        let padded = attend(
+            &q_new,
+            &cat(&k_padded, &k_new),
+            &cat(&v_padded, &v_new),
+            &[Some((k_padded.clone(), v_padded.clone()))],
+            Some(&decode_mask),
+        );
+        assert!(max_diff(&padded, &unpadded) < 1e-5);
+    }
+
     #[test]
     fn fused_bias_linear() {
         use candle_core::{DType, Device, IndexOp, Tensor};
@@ -3,6 +3,7 @@
 use std::ops::Add;
 
 use candle_core::{DType, Device, Result, Tensor, WithDType};
+use half::{bf16, f16};
 
 // https://github.com/huggingface/transformers/blob/main/src/transformers/modeling_attn_mask_utils.py
 pub struct CausalMasker;
@@ -169,6 +170,106 @@ impl CausalMasker {
         Ok(mask)
     }
 
+    /// Add the padding of `attention_mask` (bs, len), 0 for keys which must not be attended to, to `causal_mask`.
+    /// Only the last `kv_len` columns of `attention_mask` are used, as the keys of earlier positions may have
+    /// been dropped from the cache. Padding uses the most negative finite value of `dtype` rather than `-inf` so
+    /// that the rows of padding queries, which may have nothing left to attend to, do not become NaN.
+    fn add_padding_mask(
+        &self,
+        causal_mask: Option<Tensor>,
+        input_ids: &Tensor,
+        attention_mask: Option<&Tensor>,
+        kv_len: usize,
+        dtype: DType,
+        n_attn_heads: usize,
+    ) -> Result<Option<Tensor>> {
+        let Some(attention_mask) = attention_mask else {
+            return Ok(causal_mask);
+        };
+        let (b_sz, tgt_len) = input_ids.dims2()?;
+        let src_len = attention_mask.dim(1)?;
+        if src_len < kv_len {
+            candle_core::bail!(
+                "Attention mask covers {src_len} positions, but there are {kv_len} keys."
+            );
+        }
+        let attention_mask = attention_mask
+            .narrow(1, src_len - kv_len, kv_len)?
+            .to_device(input_ids.device())?
+            .to_dtype(DType::U8)?;
+        let padding = (attention_mask.ones_like()? - attention_mask)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+        let zero = Tensor::zeros(
+            (b_sz, n_attn_heads, tgt_len, kv_len),
+            dtype,
+            input_ids.device(),
+        )?;
+        let min = match dtype {
+            DType::F16 => f16::MIN.to_f32(),
+            DType::BF16 => bf16::MIN.to_f32(),
+            _ => f32::MIN,
+        };
+        let padding_mask = masked_fill(&zero, &padding, min)?;
+        match causal_mask {
+            Some(causal_mask) => Ok(Some(causal_mask.broadcast_add(&padding_mask)?)),
+            None => Ok(Some(padding_mask)),
+        }
+    }
+
+    /// Like `make_causal_mask_as_attn_bias`, additionally masking out the keys where `attention_mask`
+    /// (bs, kv len) is 0. Unlike the causal mask, this is also needed when decoding a single token.
+    pub fn make_causal_mask_with_padding_as_attn_bias(
+        &self,
+        input_ids: &Tensor,
+        cache: &[Option<(Tensor, Tensor)>],
+        attention_mask: Option<&Tensor>,
+        dtype: DType,
+        n_attn_heads: usize,
+    ) -> Result<Option<Tensor>> {
+        let causal_mask =
+            self.make_causal_mask_as_attn_bias(input_ids, cache, dtype, n_attn_heads)?;
+        let kv_len = self.calculate_past_kv_len(cache)? + input_ids.dim(1)?;
+        self.add_padding_mask(
+            causal_mask,
+            input_ids,
+            attention_mask,
+            kv_len,
+            dtype,
+            n_attn_heads,
+        )
+    }
+
+    /// Like `make_causal_mask_with_sliding_window_as_attn_bias`, additionally masking out the keys where
+    /// `attention_mask` (bs, kv len) is 0. The mask covers every cached key; the cache trims it along with
+    /// the keys once they exceed the sliding window.
+    pub fn make_causal_mask_with_sliding_window_and_padding_as_attn_bias(
+        &self,
+        input_ids: &Tensor,
+        cache: &[Option<(Tensor, Tensor)>],
+        sliding_window: Option<usize>,
+        attention_mask: Option<&Tensor>,
+        dtype: DType,
+        n_attn_heads: usize,
+    ) -> Result<Option<Tensor>> {
+        let causal_mask = self.make_causal_mask_with_sliding_window_as_attn_bias(
+            input_ids,
+            cache,
+            sliding_window,
+            dtype,
+            n_attn_heads,
+        )?;
+        let kv_len = self.calculate_past_kv_len(cache)? + input_ids.dim(1)?;
+        self.add_padding_mask(
+            causal_mask,
+            input_ids,
+            attention_mask,
+            kv_len,
+            dtype,
+            n_attn_heads,
+        )
+    }
+
     #[deprecated(
         since = "0.1.10",
         note = "use `make_causal_mask_as_attn_bias` instead! \
@@ -3,7 +3,10 @@
 use cublaslt::setup_cublas_lt_wrapper;
 use engine::Engine;
 pub use engine::TERMINATE_ALL_NEXT_STEP;
-pub use lora::Ordering;
+pub use lora::{
+    AdapterTiming, BatchingRecommendation, LoraLinear, NanError, Ordering, OverheadStats,
+    QuantizedAdapterStats, WeightIntegrityError,
+};
 use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
 #[cfg(feature = "pyo3_macros")]
@@ -33,12 +36,14 @@ pub use toml_selector::get_toml_selected_model_dtype;
 
 mod cublaslt;
 mod gguf;
+mod json_schema;
 pub mod layers;
 mod layers_masker;
 mod layers_utils;
 mod models;
 mod pipeline;
 mod prefix_cacher;
+mod prompt_cache;
 mod request;
 mod response;
 mod sampler;
@@ -50,15 +55,18 @@ mod vision_models;
 mod xlora_models;
 
 pub use device_map::{DeviceMapMetadata, LayerDeviceMapper};
+pub use json_schema::JsonSchemaConstraint;
 pub use pipeline::{
-    chat_template::ChatTemplate, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig,
-    GGUFArchitecture, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader,
-    Idefics2Loader, LlamaLoader, Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind,
-    ModelPaths, NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig,
-    Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig, SpeculativeLoader,
-    SpeculativePipeline, TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType,
-    VisionModelLoader, VisionSpecificConfig,
+    chat_template::{load_generation_config, ChatTemplate, GenerationConfig},
+    GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFArchitecture, GGUFLoader,
+    GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, Idefics2Loader, LlamaLoader, Loader,
+    LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoader,
+    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader, Phi3Loader,
+    Phi3VLoader, Qwen2Loader, SpeculativeConfig, SpeculativeLoader, SpeculativePipeline,
+    TokenSource, TruncationStrategy, TruncationWarning, VisionLoader, VisionLoaderBuilder,
+    VisionLoaderType, VisionModelLoader, VisionSpecificConfig,
 };
+pub use prompt_cache::PromptCache;
 pub use request::{Constraint, MessageContent, NormalRequest, Request, RequestMessage};
 pub use response::Response;
 pub use response::*;
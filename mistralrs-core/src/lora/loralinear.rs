@@ -1,12 +1,13 @@
-use std::{collections::HashMap, iter::zip, ops::Mul};
+use std::{collections::HashMap, iter::zip, ops::Mul, sync::Arc, time::Instant};
 
 use candle_core::{
     bail,
     quantized::{QMatMul, QTensor},
-    Module, Result, Tensor,
+    DType, Device, Module, Result, Tensor,
 };
-use candle_nn::{Linear, VarBuilder};
+use candle_nn::{init, Linear, VarBuilder};
 use either::Either;
+use thiserror::Error;
 
 use crate::layers::QLinear;
 
@@ -15,6 +16,153 @@ use super::{
     LinearLayerLike, LoraConfig, LoraLinearConfig, Merge,
 };
 
+/// INT8 weight stored as `u8` offset by 128, with a per-row absmax scale.
+#[derive(Debug)]
+struct Int8Weight {
+    q: Tensor,
+    scale: Tensor,
+    dtype: DType,
+}
+
+impl Int8Weight {
+    fn quantize(w: &Tensor) -> Result<Self> {
+        let dtype = w.dtype();
+        let w = w.to_dtype(DType::F32)?;
+        // All-zero rows (such as a freshly initialized B) would otherwise divide by zero.
+        let scale = (w.abs()?.max_keepdim(1)? / 127.)?.maximum(f32::MIN_POSITIVE)?;
+        let q = w.broadcast_div(&scale)?.round()?.clamp(-127f32, 127f32)?;
+        let q = (q + 128.)?.to_dtype(DType::U8)?;
+        Ok(Self { q, scale, dtype })
+    }
+
+    fn dequantize(&self) -> Result<Tensor> {
+        (self.q.to_dtype(DType::F32)? - 128.)?
+            .broadcast_mul(&self.scale)?
+            .to_dtype(self.dtype)
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        self.q.elem_count() * self.q.dtype().size_in_bytes()
+            + self.scale.elem_count() * self.scale.dtype().size_in_bytes()
+    }
+}
+
+/// Memory usage before and after [`LoraLinear::quantize_adapters_int8`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuantizedAdapterStats {
+    pub n_tensors: usize,
+    pub original_bytes: usize,
+    pub quantized_bytes: usize,
+}
+
+#[derive(Error, Debug)]
+pub enum WeightIntegrityError {
+    #[error("Adapter `{adapter}` {matrix} weight has shape {got:?}, expected {expected:?}.")]
+    AdapterShape {
+        adapter: String,
+        matrix: &'static str,
+        expected: Vec<usize>,
+        got: Vec<usize>,
+    },
+    #[error("There are {adapters} active adapters but {scales} scales.")]
+    ScaleCount { adapters: usize, scales: usize },
+    #[error("There are {adapters} active adapters but {names} names.")]
+    NameCount { adapters: usize, names: usize },
+    #[error("There are {adapters} active adapters but {dropouts} dropouts.")]
+    DropoutCount { adapters: usize, dropouts: usize },
+}
+
+/// An adapter weight with NaN or infinite values, from [`LoraLinear::assert_no_nan`].
+#[derive(Error, Debug)]
+#[error(
+    "Layer {layer} adapter `{adapter_name}` {matrix} weight has {n_nan} NaN or infinite values."
+)]
+pub struct NanError {
+    pub layer: usize,
+    pub adapter_name: String,
+    pub matrix: char,
+    pub n_nan: usize,
+}
+
+fn count_non_finite(w: &Tensor) -> usize {
+    w.flatten_all()
+        .and_then(|w| w.to_dtype(DType::F32)?.to_vec1::<f32>())
+        .expect("Adapter weights should be readable.")
+        .iter()
+        .filter(|x| !x.is_finite())
+        .count()
+}
+
+fn check_adapter_shape(
+    name: &str,
+    a: &Tensor,
+    b: &Tensor,
+    (out_features, in_features): (usize, usize),
+) -> std::result::Result<(), WeightIntegrityError> {
+    let rank = a.dims().first().copied().unwrap_or(0);
+    for (matrix, w, expected) in [
+        ("A", a, vec![rank, in_features]),
+        ("B", b, vec![out_features, rank]),
+    ] {
+        if w.dims() != expected {
+            return Err(WeightIntegrityError::AdapterShape {
+                adapter: name.to_string(),
+                matrix,
+                expected,
+                got: w.dims().to_vec(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Mean forward latency of a base layer and its LoRA version, from [`LoraLinear::estimate_inference_overhead`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OverheadStats {
+    pub base_mean_ms: f64,
+    pub lora_mean_ms: f64,
+    pub overhead_factor: f64,
+}
+
+/// Mean forward latency of the sequential and batched adapter paths, from
+/// [`LoraLinear::benchmark_batched_vs_sequential`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchingRecommendation {
+    pub sequential_mean_ms: f64,
+    pub batched_mean_ms: f64,
+    pub use_batched: bool,
+}
+
+/// Time spent in one adapter, from [`LoraLinear::lora_forward_with_profiling`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterTiming {
+    pub name: String,
+    pub duration_us: u64,
+}
+
+/// Mean time of `n_trials` calls to `f`, after `n_warmup` untimed calls. The output is reduced and copied
+/// to the host so that asynchronous devices have finished the work before the clock stops.
+fn mean_forward_ms(
+    f: impl Fn() -> Result<Tensor>,
+    n_warmup: usize,
+    n_trials: usize,
+) -> Result<f64> {
+    let run = || {
+        f()?.to_dtype(DType::F32)?
+            .sum_all()?
+            .to_scalar::<f32>()
+            .map(|_| ())
+    };
+    for _ in 0..n_warmup {
+        run()?;
+    }
+    let start = Instant::now();
+    for _ in 0..n_trials {
+        run()?;
+    }
+    Ok(start.elapsed().as_secs_f64() * 1000. / n_trials.max(1) as f64)
+}
+
 #[derive(Debug)]
 pub struct LoraLinear {
     old: QLinear,
@@ -24,9 +172,185 @@ pub struct LoraLinear {
     layer_n: usize,
     merged: bool,
     adapters: HashMap<String, Adapter>,
+    int8_adapters: Option<Vec<(Int8Weight, Int8Weight)>>,
+    adapter_names: Vec<String>, // Names of the active adapters, in order.
+    grad_a: Vec<Option<Tensor>>,
+    grad_b: Vec<Option<Tensor>>,
+    tied_b_adapters: Option<Arc<Vec<Linear>>>, // B weights borrowed from another layer in `new`, if any.
+}
+
+fn linear_to_dtype(linear: &Linear, dtype: DType) -> Result<Linear> {
+    Ok(Linear::new(
+        linear.weight().to_dtype(dtype)?,
+        linear.bias().map(|b| b.to_dtype(dtype)).transpose()?,
+    ))
+}
+
+fn adapters_to_dtype(
+    adapters: &Either<Vec<Linear>, (Tensor, Vec<Linear>)>,
+    dtype: DType,
+) -> Result<Either<Vec<Linear>, (Tensor, Vec<Linear>)>> {
+    let convert = |adapters: &[Linear]| {
+        adapters
+            .iter()
+            .map(|a| linear_to_dtype(a, dtype))
+            .collect::<Result<Vec<_>>>()
+    };
+    Ok(match adapters {
+        Either::Left(a) => Either::Left(convert(a)?),
+        Either::Right((stack, a)) => Either::Right((stack.to_dtype(dtype)?, convert(a)?)),
+    })
+}
+
+/// Stack the adapter weights for the batched path. The scales are folded into the A stack.
+fn stack_adapters(a: &[Linear], b: &[Linear], scales: &[f64]) -> Result<(Tensor, Tensor)> {
+    let a_adapters_stack = Tensor::cat(
+        &a.iter()
+            .map(|x| x.weight().unsqueeze(0))
+            .collect::<Result<Vec<_>>>()?,
+        0,
+    )?;
+    let b_adapters_stack = Tensor::cat(
+        &b.iter()
+            .map(|x| x.weight().unsqueeze(0))
+            .collect::<Result<Vec<_>>>()?,
+        0,
+    )?;
+    let scale_adapters_t = Tensor::from_vec(
+        scales.to_vec(),
+        (scales.len(), 1, 1),
+        a_adapters_stack.device(),
+    )?
+    .to_dtype(a_adapters_stack.dtype())?;
+    let a_adapters_stack = a_adapters_stack.broadcast_mul(&scale_adapters_t)?;
+    Ok((a_adapters_stack, b_adapters_stack))
+}
+
+fn accumulate_grad(slot: &mut Option<Tensor>, weight: &Tensor, grad: Tensor) -> Result<()> {
+    if grad.dims() != weight.dims() {
+        bail!(
+            "Expected a gradient of shape {:?}, got {:?}.",
+            weight.dims(),
+            grad.dims()
+        );
+    }
+    *slot = Some(match slot.take() {
+        Some(acc) => (acc + grad)?,
+        None => grad,
+    });
+    Ok(())
+}
+
+fn sgd_update(adapters: &mut [Linear], grads: &[Option<Tensor>], lr: f64) -> Result<()> {
+    for (adapter, grad) in adapters.iter_mut().zip(grads) {
+        if let Some(grad) = grad {
+            let w = (adapter.weight() - (grad * lr)?)?;
+            *adapter = Linear::new(w, None);
+        }
+    }
+    Ok(())
 }
 
 impl LoraLinear {
+    /// Measure the latency of `lora`'s forward pass relative to `base`, which should be the layer it wraps.
+    pub fn estimate_inference_overhead(
+        base: &dyn LinearLayerLike,
+        lora: &LoraLinear,
+        input: &Tensor,
+        n_warmup: usize,
+        n_trials: usize,
+    ) -> Result<OverheadStats> {
+        let base_mean_ms = mean_forward_ms(
+            || base.lora_forward(input, None, 1., None),
+            n_warmup,
+            n_trials,
+        )?;
+        let lora_mean_ms = mean_forward_ms(
+            || lora.lora_forward(input, None, 1., None),
+            n_warmup,
+            n_trials,
+        )?;
+        Ok(OverheadStats {
+            base_mean_ms,
+            lora_mean_ms,
+            overhead_factor: lora_mean_ms / base_mean_ms,
+        })
+    }
+
+    /// Time `n_adapters` random adapters of rank 16 applied one by one (`Either::Left`) and as a
+    /// single batched matmul (`Either::Right`) on a `(batch_size, seq_len, in_features)` input, and recommend
+    /// the faster path.
+    pub fn benchmark_batched_vs_sequential(
+        config: &LoraLinearConfig,
+        n_adapters: usize,
+        batch_size: usize,
+        seq_len: usize,
+        device: &Device,
+    ) -> Result<BatchingRecommendation> {
+        const BENCHMARK_RANK: usize = 16;
+        const N_WARMUP: usize = 2;
+        const N_TRIALS: usize = 10;
+        let (in_features, out_features) = (config.in_features, config.out_features);
+        let base = Tensor::randn(0f32, 1., (out_features, in_features), device)?;
+        let mut a_adapters = Vec::with_capacity(n_adapters);
+        let mut b_adapters = Vec::with_capacity(n_adapters);
+        for _ in 0..n_adapters {
+            a_adapters.push(Linear::new(
+                Tensor::randn(0f32, 1., (BENCHMARK_RANK, in_features), device)?,
+                None,
+            ));
+            b_adapters.push(Linear::new(
+                Tensor::randn(0f32, 1., (out_features, BENCHMARK_RANK), device)?,
+                None,
+            ));
+        }
+        let scale_adapters = vec![1.; n_adapters];
+        let adapter_names = (0..n_adapters).map(|i| i.to_string()).collect::<Vec<_>>();
+        let make = |a_adapters, b_adapters| LoraLinear {
+            old: QLinear::from_parts(base.clone(), None),
+            a_adapters,
+            b_adapters,
+            scale_adapters: scale_adapters.clone(),
+            layer_n: 0,
+            merged: false,
+            adapters: HashMap::new(),
+            int8_adapters: None,
+            adapter_names: adapter_names.clone(),
+            grad_a: vec![None; n_adapters],
+            grad_b: vec![None; n_adapters],
+            tied_b_adapters: None,
+        };
+        let (a_stack, b_stack) = stack_adapters(&a_adapters, &b_adapters, &scale_adapters)?;
+        let sequential = make(
+            Either::Left(a_adapters.clone()),
+            Either::Left(b_adapters.clone()),
+        );
+        let batched = make(
+            Either::Right((a_stack, a_adapters)),
+            Either::Right((b_stack, b_adapters)),
+        );
+
+        let input = Tensor::randn(0f32, 1., (batch_size, seq_len, in_features), device)?;
+        let sequential_mean_ms = mean_forward_ms(
+            || sequential.lora_forward(&input, None, 1., None),
+            N_WARMUP,
+            N_TRIALS,
+        )?;
+        let batched_mean_ms = mean_forward_ms(
+            || batched.lora_forward(&input, None, 1., None),
+            N_WARMUP,
+            N_TRIALS,
+        )?;
+        Ok(BatchingRecommendation {
+            sequential_mean_ms,
+            batched_mean_ms,
+            use_batched: batched_mean_ms < sequential_mean_ms,
+        })
+    }
+
+    /// With `tie_b_with`, the B matrices of the active adapters are borrowed from that layer instead of the
+    /// loaded ones, which must have the same shapes. Training replaces the weights of the layer being stepped,
+    /// which ends the tying for that layer.
     pub fn new(
         old: &dyn LinearLayerLike,
         linear_config: &LoraLinearConfig,
@@ -34,6 +358,7 @@ impl LoraLinear {
         vb: &VarBuilder,
         layer_n: usize,
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+        tie_b_with: Option<Arc<LoraLinear>>,
     ) -> Result<Self> {
         let mut a_adapters = Vec::with_capacity(config.len());
         let mut b_adapters = Vec::with_capacity(config.len());
@@ -43,6 +368,7 @@ impl LoraLinear {
         let mut state = None;
         let mut all_same = true;
         let mut adapters = HashMap::new();
+        let mut adapter_names = Vec::with_capacity(config.len());
         for ((name_id, adapter_name), cfg) in config.iter() {
             let a_pp = a_vb.pp(name_id);
             let b_pp = b_vb.pp(name_id);
@@ -71,8 +397,41 @@ impl LoraLinear {
                 all_same = false;
             }
             adapters.insert(adapter_name.clone(), adapter);
+            adapter_names.push(adapter_name.clone());
         }
 
+        // Tie the B matrices of the active adapters to those of `tie_b_with`, so both layers share their storage.
+        let tied_b_adapters = match tie_b_with {
+            Some(tie) => {
+                let tied = match &tie.tied_b_adapters {
+                    Some(tied) => tied.clone(),
+                    None => Arc::new(tie.active_adapters().1.clone()),
+                };
+                if tied.len() != b_adapters.len() {
+                    bail!(
+                        "Cannot tie {} B adapters to a layer with {}.",
+                        b_adapters.len(),
+                        tied.len()
+                    );
+                }
+                for ((b, tied_b), name) in zip(zip(&mut b_adapters, tied.iter()), &adapter_names) {
+                    if b.weight().dims() != tied_b.weight().dims() {
+                        bail!(
+                            "Cannot tie B adapter `{name}` of shape {:?} to one of shape {:?}.",
+                            b.weight().dims(),
+                            tied_b.weight().dims()
+                        );
+                    }
+                    *b = tied_b.clone();
+                    if let Some(adapter) = adapters.get_mut(name) {
+                        adapter.b = tied_b.clone();
+                    }
+                }
+                Some(tied)
+            }
+            None => None,
+        };
+
         if let Some(preload_adapters) = preload_adapters {
             all_same = false;
             for (name, (vb, cfg)) in preload_adapters {
@@ -83,39 +442,26 @@ impl LoraLinear {
             }
         }
 
-        if all_same {
-            let a_adapters_stack = Tensor::cat(
-                &a_adapters
-                    .iter()
-                    .map(|x| x.weight().unsqueeze(0))
-                    .collect::<Result<Vec<_>>>()?,
-                0,
-            )?;
-            let b_adapters_stack = Tensor::cat(
-                &b_adapters
-                    .iter()
-                    .map(|x| x.weight().unsqueeze(0))
-                    .collect::<Result<Vec<_>>>()?,
-                0,
-            )?;
-            let scale_adapters_t = Tensor::from_vec(
-                scale_adapters.clone(),
-                (scale_adapters.len(), 1, 1),
-                a_adapters_stack.device(),
-            )?
-            .to_dtype(a_adapters_stack.dtype())?;
-            let a_adapters_stack = a_adapters_stack.broadcast_mul(&scale_adapters_t)?;
-            Ok(LoraLinear {
+        let n_adapters = adapter_names.len();
+        let lora = if all_same {
+            let (a_adapters_stack, b_adapters_stack) =
+                stack_adapters(&a_adapters, &b_adapters, &scale_adapters)?;
+            LoraLinear {
                 old: QLinear::from_parts(old.weight().clone(), old.bias().cloned()),
-                a_adapters: Either::Right((a_adapters_stack.clone(), a_adapters)),
+                a_adapters: Either::Right((a_adapters_stack, a_adapters)),
                 b_adapters: Either::Right((b_adapters_stack, b_adapters)),
                 scale_adapters,
                 layer_n,
                 merged: false,
                 adapters,
-            })
+                int8_adapters: None,
+                adapter_names,
+                grad_a: vec![None; n_adapters],
+                grad_b: vec![None; n_adapters],
+                tied_b_adapters,
+            }
         } else {
-            Ok(LoraLinear {
+            LoraLinear {
                 old: QLinear::from_parts(old.weight().clone(), old.bias().cloned()),
                 a_adapters: Either::Left(a_adapters),
                 b_adapters: Either::Left(b_adapters),
@@ -123,13 +469,760 @@ impl LoraLinear {
                 layer_n,
                 merged: false,
                 adapters,
+                int8_adapters: None,
+                adapter_names,
+                grad_a: vec![None; n_adapters],
+                grad_b: vec![None; n_adapters],
+                tied_b_adapters,
+            }
+        };
+        lora.check_weight_integrity()
+            .map_err(candle_core::Error::msg)?;
+        Ok(lora)
+    }
+
+    /// One `LoraLinear` per base layer, all applying the same adapter. Tensors are reference counted, so every
+    /// layer shares the storage of `shared_a` and `shared_b` rather than holding a copy. Training replaces the
+    /// weights of the layer being stepped, which ends the sharing for that layer.
+    pub fn from_shared_adapters(
+        layers: Vec<&dyn LinearLayerLike>,
+        shared_a: Tensor,
+        shared_b: Tensor,
+        scale: f64,
+        layer_n: usize,
+    ) -> Result<Vec<LoraLinear>> {
+        const SHARED_ADAPTER_NAME: &str = "shared";
+        let a = Linear::new(shared_a, None);
+        let b = Linear::new(shared_b, None);
+        layers
+            .into_iter()
+            .map(|old| {
+                if old.is_quant() {
+                    bail!("Cannot share adapters over a quantized base layer.");
+                }
+                let lora = LoraLinear {
+                    old: QLinear::from_parts(old.weight().clone(), old.bias().cloned()),
+                    a_adapters: Either::Left(vec![a.clone()]),
+                    b_adapters: Either::Left(vec![b.clone()]),
+                    scale_adapters: vec![scale],
+                    layer_n,
+                    merged: false,
+                    adapters: HashMap::from([(
+                        SHARED_ADAPTER_NAME.to_string(),
+                        Adapter {
+                            a: a.clone(),
+                            b: b.clone(),
+                            scale,
+                            dropout: 0.,
+                        },
+                    )]),
+                    int8_adapters: None,
+                    adapter_names: vec![SHARED_ADAPTER_NAME.to_string()],
+                    grad_a: vec![None],
+                    grad_b: vec![None],
+                    tied_b_adapters: None,
+                };
+                lora.check_weight_integrity()
+                    .map_err(candle_core::Error::msg)?;
+                Ok(lora)
             })
+            .collect()
+    }
+
+    /// Merge the `(name, A, B, scale)` adapters into `base` at once, adding `Σ B_i * A_i * scale_i` to the
+    /// base weight. A quantized base is dequantized and requantized only once.
+    pub fn merge_multiple(
+        base: QLinear,
+        adapters: Vec<(&str, Tensor, Tensor, f64)>,
+    ) -> Result<QLinear> {
+        let (w_base, qdtype) = match base.inner_ref() {
+            QMatMul::QTensor(q) => (q.dequantize(&q.device())?, Some(q.dtype())),
+            QMatMul::Tensor(w) | QMatMul::TensorF16(w) => (w.clone(), None),
+        };
+        let features = (w_base.dim(0)?, w_base.dim(w_base.rank() - 1)?);
+
+        let mut delta: Option<Tensor> = None;
+        for (name, a, b, scale) in &adapters {
+            check_adapter_shape(name, a, b, features).map_err(candle_core::Error::msg)?;
+            let res = (b.matmul(a)? * *scale)?.to_dtype(w_base.dtype())?;
+            delta = Some(match delta {
+                Some(delta) => (delta + res)?,
+                None => res,
+            });
+        }
+        let Some(delta) = delta else {
+            return Ok(base);
+        };
+
+        let w_merged = (w_base + delta)?;
+        let bias = base.bias().cloned();
+        Ok(match qdtype {
+            Some(qdtype) => QLinear::from_qparts(QTensor::quantize(&w_merged, qdtype)?, bias),
+            None => QLinear::from_parts(w_merged, bias),
+        })
+    }
+
+    /// Wrap a base weight which already has adapters merged into it (`W + B*A`) and apply new adapters on top.
+    /// Each adapter in `config` is loaded from `vb` under its name, as in `new`.
+    pub fn from_merged_weight(
+        merged_weight: Tensor,
+        bias: Option<Tensor>,
+        config: &[(String, LoraConfig)],
+        vb: &VarBuilder,
+        layer_n: usize,
+    ) -> Result<Self> {
+        let (out_features, in_features) = merged_weight.dims2()?;
+        let config = config
+            .iter()
+            .map(|(name, cfg)| ((name.clone(), name.clone()), cfg.clone()))
+            .collect::<Vec<_>>();
+        Self::new(
+            &Linear::new(merged_weight, bias),
+            &LoraLinearConfig::new(in_features, out_features),
+            &config,
+            vb,
+            layer_n,
+            &None,
+            None,
+        )
+    }
+
+    /// Forward through the base layer only, without any adapters. This can be cached and combined
+    /// with `adapter_delta` when only the adapter weighting changes.
+    pub fn base_forward(&self, input: &Tensor) -> Result<Tensor> {
+        self.old.forward(input)
+    }
+
+    /// The summed contribution of the active adapters to the output, or `None` if there is nothing to add
+    /// (no adapters, or they have been merged into the base weight).
+    pub fn adapter_delta(
+        &self,
+        input: &Tensor,
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+    ) -> Result<Option<Tensor>> {
+        if self.merged {
+            return Ok(None);
+        }
+
+        let scalings =
+            scalings.map(|scalings| get_maybe_topk_scalings(scalings, self.layer_n).unwrap());
+        let mut delta: Option<Tensor> = None;
+        if let Some(int8_adapters) = &self.int8_adapters {
+            for (i, ((q_a, q_b), adapter_scale)) in
+                zip(int8_adapters, &self.scale_adapters).enumerate()
+            {
+                let adapter_a = Linear::new(q_a.dequantize()?, None);
+                let adapter_b = Linear::new(q_b.dequantize()?, None);
+                let input_new = input.to_dtype(adapter_a.weight().dtype())?;
+                let input_new = if let Some(scalings) = &scalings {
+                    apply_scalings_to_x(input_new, scalings, i)?
+                } else {
+                    input_new
+                };
+
+                let res = adapter_b
+                    .forward(&adapter_a.forward(&input_new)?)?
+                    .mul(*adapter_scale)?
+                    .mul(global_scaling_weight)?;
+                delta = Some(match delta {
+                    Some(delta) => (delta + res)?,
+                    None => res,
+                });
+            }
+            return Ok(delta);
+        }
+        if self.a_adapters.is_left()
+            || scalings
+                .as_ref()
+                .is_some_and(|scalings| scalings.dims3().unwrap().1 != 1)
+        {
+            let (a_adapters, b_adapters) = self.active_adapters();
+            //No fan_in_fan_out so no weight.transpose(0,1)
+            for (i, (adapter_a, (adapter_b, adapter_scale))) in
+                zip(a_adapters, zip(b_adapters, &self.scale_adapters)).enumerate()
+            {
+                let input_new = input.to_dtype(adapter_a.weight().dtype())?;
+                let input_new = if let Some(scalings) = &scalings {
+                    apply_scalings_to_x(input_new, scalings, i)?
+                } else {
+                    input_new
+                };
+
+                let res = adapter_b
+                    .forward(&adapter_a.forward(&input_new)?)?
+                    .mul(*adapter_scale)?
+                    .mul(global_scaling_weight)?;
+                delta = Some(match delta {
+                    Some(delta) => (delta + res)?,
+                    None => res,
+                });
+            }
+            Ok(delta)
+        } else {
+            let adapter_a = &self.a_adapters.as_ref().unwrap_right().0;
+            let adapter_b = &self.b_adapters.as_ref().unwrap_right().0;
+            let adapter_scales = &self.scale_adapters;
+            let n_adapters = adapter_scales.len();
+            let adapter_a = if let Some(scalings) = scalings.as_ref() {
+                let scalings = scalings
+                    .squeeze(0)?
+                    .squeeze(0)?
+                    .unsqueeze(1)?
+                    .unsqueeze(1)?;
+                adapter_a
+                    .broadcast_mul(&scalings)?
+                    .mul(global_scaling_weight)?
+            } else {
+                adapter_a.clone().mul(global_scaling_weight)?
+            };
+
+            let (b, s, h) = input.dims3()?;
+            let input = input.reshape((b * s, h))?;
+            let out = adapter_a.broadcast_matmul(&input.t()?)?;
+            let out = adapter_b.broadcast_matmul(&out)?;
+            let o_h = out.dims()[1];
+            let out = out.reshape((n_adapters, b, s, o_h))?;
+            Ok(Some(out.sum(0)?))
         }
     }
+
+    /// Like `lora_forward`, but also returns the time spent in each active adapter. When the adapters are
+    /// applied as one batched matmul, that time is split evenly between them. The device is synchronized
+    /// around each timed section, as the kernels of asynchronous devices would otherwise still be running.
+    pub fn lora_forward_with_profiling(
+        &self,
+        input: &Tensor,
+        scalings: Tensor,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<(Tensor, Vec<AdapterTiming>)> {
+        let result = self.base_forward(input)?;
+        if self.merged || is_scaling_pass.is_some_and(|x| x == 0.) {
+            return Ok((result, Vec::new()));
+        }
+
+        let layer_scalings = get_maybe_topk_scalings(scalings.clone(), self.layer_n)?;
+        let device = input.device();
+        if self.int8_adapters.is_none()
+            && self.a_adapters.is_right()
+            && layer_scalings.dims3()?.1 == 1
+        {
+            device.synchronize()?;
+            let start = Instant::now();
+            let delta = self.adapter_delta(input, Some(scalings), global_scaling_weight)?;
+            device.synchronize()?;
+            let duration_us =
+                start.elapsed().as_micros() as u64 / self.adapter_names.len().max(1) as u64;
+            let timings = self
+                .adapter_names
+                .iter()
+                .map(|name| AdapterTiming {
+                    name: name.clone(),
+                    duration_us,
+                })
+                .collect();
+            let result = match delta {
+                Some(delta) => (delta + result)?,
+                None => result,
+            };
+            return Ok((result, timings));
+        }
+
+        let mut result = result;
+        let mut timings = Vec::with_capacity(self.adapter_names.len());
+        for (i, name) in self.adapter_names.iter().enumerate() {
+            device.synchronize()?;
+            let start = Instant::now();
+            let (w_a, w_b) = self.adapter_weights(i)?;
+            let input_new = input.to_dtype(w_a.dtype())?;
+            let input_new = apply_scalings_to_x(input_new, &layer_scalings, i)?;
+            let res = Linear::new(w_b, None)
+                .forward(&Linear::new(w_a, None).forward(&input_new)?)?
+                .mul(self.scale_adapters[i])?
+                .mul(global_scaling_weight)?;
+            result = (result + res)?;
+            device.synchronize()?;
+            timings.push(AdapterTiming {
+                name: name.clone(),
+                duration_us: start.elapsed().as_micros() as u64,
+            });
+        }
+        Ok((result, timings))
+    }
+
+    /// Monte Carlo dropout: run `n_samples` forward passes with each adapter's LoRA dropout applied to its
+    /// input, and return the elementwise mean and variance of the outputs. Adapters trained without dropout,
+    /// or quantized adapters whose configuration was dropped, contribute no variance.
+    pub fn mc_dropout_forward(
+        &self,
+        input: &Tensor,
+        scalings: Tensor,
+        global_scaling_weight: f64,
+        n_samples: usize,
+    ) -> Result<(Tensor, Tensor)> {
+        if n_samples == 0 {
+            bail!("MC dropout needs at least one sample.");
+        }
+        let base = self.base_forward(input)?;
+        if self.merged {
+            return Ok((base.clone(), base.zeros_like()?));
+        }
+
+        let scalings = get_maybe_topk_scalings(scalings, self.layer_n)?;
+        let base_f32 = base.to_dtype(DType::F32)?;
+        let mut sum = base_f32.zeros_like()?;
+        let mut sum_sq = base_f32.zeros_like()?;
+        for _ in 0..n_samples {
+            let mut out = base_f32.clone();
+            for (i, name) in self.adapter_names.iter().enumerate() {
+                let (w_a, w_b) = self.adapter_weights(i)?;
+                let dropout = self.adapters.get(name).map_or(0., |a| a.dropout);
+                let input_new = input.to_dtype(w_a.dtype())?;
+                let input_new = apply_scalings_to_x(input_new, &scalings, i)?;
+                let input_new = if dropout > 0. {
+                    candle_nn::ops::dropout(&input_new, dropout)?
+                } else {
+                    input_new
+                };
+                let res = Linear::new(w_b, None)
+                    .forward(&Linear::new(w_a, None).forward(&input_new)?)?
+                    .mul(self.scale_adapters[i])?
+                    .mul(global_scaling_weight)?;
+                out = (out + res.to_dtype(DType::F32)?)?;
+            }
+            sum = (sum + &out)?;
+            sum_sq = (sum_sq + out.sqr()?)?;
+        }
+        let mean = (sum / n_samples as f64)?;
+        // Clamp the rounding error, which can make E[x^2] - E[x]^2 slightly negative.
+        let variance = ((sum_sq / n_samples as f64)? - mean.sqr()?)?.maximum(0f32)?;
+        Ok((
+            mean.to_dtype(base.dtype())?,
+            variance.to_dtype(base.dtype())?,
+        ))
+    }
+
+    /// The Frobenius norm of each active adapter's contribution to the output for `input`, largest first.
+    pub fn adapter_forward_contribution(
+        &self,
+        input: &Tensor,
+        scalings: Tensor,
+    ) -> Result<Vec<(String, f64)>> {
+        let scalings = get_maybe_topk_scalings(scalings, self.layer_n)?;
+        let mut contributions = Vec::with_capacity(self.adapter_names.len());
+        for (i, name) in self.adapter_names.iter().enumerate() {
+            let (w_a, w_b) = self.adapter_weights(i)?;
+            let input_new = input.to_dtype(w_a.dtype())?;
+            let input_new = apply_scalings_to_x(input_new, &scalings, i)?;
+            let res = Linear::new(w_b, None)
+                .forward(&Linear::new(w_a, None).forward(&input_new)?)?
+                .mul(self.scale_adapters[i])?;
+            let norm = res
+                .to_dtype(DType::F64)?
+                .sqr()?
+                .sum_all()?
+                .sqrt()?
+                .to_scalar::<f64>()?;
+            contributions.push((name.clone(), norm));
+        }
+        contributions.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("No ordering."));
+        Ok(contributions)
+    }
+
+    /// Check that every adapter's A weight is `(rank, in_features)` and B weight is `(out_features, rank)`,
+    /// with the features of the base layer, and that there is a scale, a name and a dropout for every active
+    /// adapter. The dropout of an active adapter is the one stored with its weights under its name.
+    pub fn check_weight_integrity(&self) -> std::result::Result<(), WeightIntegrityError> {
+        let base_dims = match self.old.inner_ref() {
+            QMatMul::QTensor(q) => q.shape().dims().to_vec(),
+            QMatMul::Tensor(w) | QMatMul::TensorF16(w) => w.dims().to_vec(),
+        };
+        let features = (base_dims[0], base_dims[base_dims.len() - 1]);
+
+        let n_adapters = match &self.int8_adapters {
+            Some(int8_adapters) => {
+                for (name, (q_a, q_b)) in zip(&self.adapter_names, int8_adapters) {
+                    check_adapter_shape(name, &q_a.q, &q_b.q, features)?;
+                }
+                int8_adapters.len()
+            }
+            None => {
+                let (a_adapters, b_adapters) = self.active_adapters();
+                for (name, (a, b)) in zip(&self.adapter_names, zip(a_adapters, b_adapters)) {
+                    check_adapter_shape(name, a.weight(), b.weight(), features)?;
+                }
+                a_adapters.len()
+            }
+        };
+        for (name, adapter) in &self.adapters {
+            check_adapter_shape(name, adapter.a.weight(), adapter.b.weight(), features)?;
+        }
+        if self.scale_adapters.len() != n_adapters {
+            return Err(WeightIntegrityError::ScaleCount {
+                adapters: n_adapters,
+                scales: self.scale_adapters.len(),
+            });
+        }
+        if self.adapter_names.len() != n_adapters {
+            return Err(WeightIntegrityError::NameCount {
+                adapters: n_adapters,
+                names: self.adapter_names.len(),
+            });
+        }
+        let n_dropouts = self
+            .adapter_names
+            .iter()
+            .filter(|name| self.adapters.contains_key(*name))
+            .count();
+        if n_dropouts != n_adapters {
+            return Err(WeightIntegrityError::DropoutCount {
+                adapters: n_adapters,
+                dropouts: n_dropouts,
+            });
+        }
+        Ok(())
+    }
+
+    /// A copy with the adapter weights, including inactive preloaded adapters and accumulated gradients,
+    /// converted to `dtype`. The base layer is kept as is; the adapter output is cast to its dtype.
+    pub fn to_dtype(&self, dtype: DType) -> Result<LoraLinear> {
+        let convert_grads = |grads: &[Option<Tensor>]| {
+            grads
+                .iter()
+                .map(|g| g.as_ref().map(|g| g.to_dtype(dtype)).transpose())
+                .collect::<Result<Vec<_>>>()
+        };
+        let lora = LoraLinear {
+            old: self.old.clone(),
+            a_adapters: adapters_to_dtype(&self.a_adapters, dtype)?,
+            b_adapters: adapters_to_dtype(&self.b_adapters, dtype)?,
+            scale_adapters: self.scale_adapters.clone(),
+            layer_n: self.layer_n,
+            merged: self.merged,
+            adapters: self
+                .adapters
+                .iter()
+                .map(|(name, adapter)| {
+                    Ok((
+                        name.clone(),
+                        Adapter {
+                            a: linear_to_dtype(&adapter.a, dtype)?,
+                            b: linear_to_dtype(&adapter.b, dtype)?,
+                            scale: adapter.scale,
+                            dropout: adapter.dropout,
+                        },
+                    ))
+                })
+                .collect::<Result<_>>()?,
+            int8_adapters: self.int8_adapters.as_ref().map(|int8_adapters| {
+                int8_adapters
+                    .iter()
+                    .map(|(q_a, q_b)| {
+                        let convert = |q: &Int8Weight| Int8Weight {
+                            q: q.q.clone(),
+                            scale: q.scale.clone(),
+                            dtype,
+                        };
+                        (convert(q_a), convert(q_b))
+                    })
+                    .collect()
+            }),
+            adapter_names: self.adapter_names.clone(),
+            grad_a: convert_grads(&self.grad_a)?,
+            grad_b: convert_grads(&self.grad_b)?,
+            tied_b_adapters: None,
+        };
+        lora.check_weight_integrity()
+            .map_err(candle_core::Error::msg)?;
+        Ok(lora)
+    }
+
+    /// `to_dtype(DType::F16)`.
+    pub fn to_half(&self) -> Result<LoraLinear> {
+        self.to_dtype(DType::F16)
+    }
+
+    /// `to_dtype(DType::BF16)`.
+    pub fn to_bfloat16(&self) -> Result<LoraLinear> {
+        self.to_dtype(DType::BF16)
+    }
+
+    /// The dtype of the first active adapter's A weight, or of the base weight if there are no adapters.
+    pub fn current_dtype(&self) -> DType {
+        if let Some(int8_adapters) = &self.int8_adapters {
+            if let Some((q_a, _)) = int8_adapters.first() {
+                return q_a.dtype;
+            }
+        }
+        match self.active_adapters().0.first() {
+            Some(a) => a.weight().dtype(),
+            None => match self.old.inner_ref() {
+                QMatMul::QTensor(_) => DType::F32,
+                QMatMul::Tensor(w) | QMatMul::TensorF16(w) => w.dtype(),
+            },
+        }
+    }
+
+    /// Quantize the active adapters' A and B weights to INT8 with per-row absmax scaling.
+    /// The full precision weights, including any inactive preloaded adapters, are dropped and
+    /// `lora_forward` dequantizes on the fly. Adapters can no longer be swapped afterwards.
+    pub fn quantize_adapters_int8(&mut self) -> Result<QuantizedAdapterStats> {
+        if self.merged {
+            bail!("Cannot quantize adapters which have been merged.");
+        }
+        if self.int8_adapters.is_some() {
+            bail!("Adapters are already quantized.");
+        }
+        let (a_adapters, b_adapters) = self.active_adapters();
+
+        let mut stats = QuantizedAdapterStats {
+            n_tensors: 0,
+            original_bytes: 0,
+            quantized_bytes: 0,
+        };
+        let mut int8_adapters = Vec::with_capacity(a_adapters.len());
+        for (a, b) in zip(a_adapters, b_adapters) {
+            let q_a = Int8Weight::quantize(a.weight())?;
+            let q_b = Int8Weight::quantize(b.weight())?;
+            for (w, q) in [(a.weight(), &q_a), (b.weight(), &q_b)] {
+                stats.n_tensors += 1;
+                stats.original_bytes += w.elem_count() * w.dtype().size_in_bytes();
+                stats.quantized_bytes += q.size_in_bytes();
+            }
+            int8_adapters.push((q_a, q_b));
+        }
+
+        self.int8_adapters = Some(int8_adapters);
+        self.a_adapters = Either::Left(Vec::new());
+        self.b_adapters = Either::Left(Vec::new());
+        self.adapters.clear();
+        Ok(stats)
+    }
+
+    /// The A and B weights of the `i`th active adapter, dequantized if needed.
+    fn adapter_weights(&self, i: usize) -> Result<(Tensor, Tensor)> {
+        match &self.int8_adapters {
+            Some(int8_adapters) => {
+                let (q_a, q_b) = &int8_adapters[i];
+                Ok((q_a.dequantize()?, q_b.dequantize()?))
+            }
+            None => {
+                let (a, b) = self.active_adapters();
+                Ok((a[i].weight().clone(), b[i].weight().clone()))
+            }
+        }
+    }
+
+    fn active_adapters(&self) -> (&Vec<Linear>, &Vec<Linear>) {
+        match (&self.a_adapters, &self.b_adapters) {
+            (Either::Left(a), Either::Left(b)) | (Either::Right((_, a)), Either::Right((_, b))) => {
+                (a, b)
+            }
+            _ => unreachable!("Both adapters must be Either::Left or Either::Right."),
+        }
+    }
+
+    /// Rebuild the stacked tensors of the batched path after the per-adapter weights or scales change.
+    fn restack_adapters(&mut self) -> Result<()> {
+        if let (Either::Right((a_stack, a)), Either::Right((b_stack, b))) =
+            (&mut self.a_adapters, &mut self.b_adapters)
+        {
+            (*a_stack, *b_stack) = stack_adapters(a, b, &self.scale_adapters)?;
+        }
+        Ok(())
+    }
+
+    /// Write the active adapter weights back to the adapter store, so they survive adapter swapping.
+    fn sync_adapter_store(&mut self) {
+        let (a_adapters, b_adapters) = match (&self.a_adapters, &self.b_adapters) {
+            (Either::Left(a), Either::Left(b)) | (Either::Right((_, a)), Either::Right((_, b))) => {
+                (a, b)
+            }
+            _ => unreachable!("Both adapters must be Either::Left or Either::Right."),
+        };
+        for (i, name) in self.adapter_names.iter().enumerate() {
+            if let Some(adapter) = self.adapters.get_mut(name) {
+                adapter.a = a_adapters[i].clone();
+                adapter.b = b_adapters[i].clone();
+                adapter.scale = self.scale_adapters[i];
+            }
+        }
+    }
+
+    /// Set the scale (usually `alpha / rank`) of an active adapter. A scale of zero disables the adapter.
+    pub fn set_scale_for_adapter(&mut self, name: &str, new_scale: f64) -> Result<()> {
+        if self.merged {
+            bail!("Cannot change the scale of adapters which have been merged.");
+        }
+        let Some(i) = self.adapter_names.iter().position(|x| x == name) else {
+            bail!("No active adapter `{name}`.");
+        };
+        self.scale_adapters[i] = new_scale;
+        if let Some(adapter) = self.adapters.get_mut(name) {
+            adapter.scale = new_scale;
+        }
+        self.restack_adapters()
+    }
+
+    fn get_adapter(&self, name: &str) -> Result<&Adapter> {
+        match self.adapters.get(name) {
+            Some(adapter) => Ok(adapter),
+            None => bail!("No adapter `{name}`."),
+        }
+    }
+
+    /// `(alpha * A_a + (1 - alpha) * A_b, alpha * B_a + (1 - alpha) * B_b)` for the adapters `name_a` and
+    /// `name_b`, which must have the same rank.
+    pub fn interpolate_adapters(
+        &self,
+        name_a: &str,
+        name_b: &str,
+        alpha: f64,
+    ) -> Result<(Tensor, Tensor)> {
+        let adapter_a = self.get_adapter(name_a)?;
+        let adapter_b = self.get_adapter(name_b)?;
+        let lerp = |x: &Tensor, y: &Tensor| -> Result<Tensor> {
+            if x.dims() != y.dims() {
+                bail!(
+                    "Cannot interpolate `{name_a}` and `{name_b}`, their weights have shapes {:?} and {:?}.",
+                    x.dims(),
+                    y.dims()
+                );
+            }
+            (x * alpha)? + (y.to_dtype(x.dtype())? * (1. - alpha))?
+        };
+        Ok((
+            lerp(adapter_a.a.weight(), adapter_b.a.weight())?,
+            lerp(adapter_a.b.weight(), adapter_b.b.weight())?,
+        ))
+    }
+
+    /// Register the interpolation of `name_a` and `name_b` as the adapter `new_name`, with the scale and
+    /// dropout interpolated the same way. It can then be activated like any other adapter.
+    pub fn create_interpolated_adapter(
+        &mut self,
+        name_a: &str,
+        name_b: &str,
+        alpha: f64,
+        new_name: &str,
+    ) -> Result<()> {
+        if self.adapters.contains_key(new_name) {
+            bail!("There is already an adapter `{new_name}`.");
+        }
+        let (a, b) = self.interpolate_adapters(name_a, name_b, alpha)?;
+        let adapter_a = self.get_adapter(name_a)?;
+        let adapter_b = self.get_adapter(name_b)?;
+        let scale = alpha * adapter_a.scale + (1. - alpha) * adapter_b.scale;
+        let dropout =
+            (alpha * adapter_a.dropout as f64 + (1. - alpha) * adapter_b.dropout as f64) as f32;
+        self.adapters.insert(
+            new_name.to_string(),
+            Adapter {
+                a: Linear::new(a, None),
+                b: Linear::new(b, None),
+                scale,
+                dropout,
+            },
+        );
+        Ok(())
+    }
+
+    /// Reinitialize the active adapters as if freshly created: A with Kaiming normal and B with zeros, so the
+    /// output equals the base layer's. The base weight and the adapter scales are kept.
+    pub fn reinit_adapters(&mut self, device: &Device, dtype: DType) -> Result<()> {
+        if self.merged || self.int8_adapters.is_some() {
+            bail!("Cannot reinitialize adapters which have been merged or quantized.");
+        }
+        match (&mut self.a_adapters, &mut self.b_adapters) {
+            (Either::Left(a), Either::Left(b)) | (Either::Right((_, a)), Either::Right((_, b))) => {
+                for (a, b) in a.iter_mut().zip(b.iter_mut()) {
+                    let new_a = init::DEFAULT_KAIMING_NORMAL
+                        .var(a.weight().shape(), dtype, device)?
+                        .as_detached_tensor();
+                    let new_b = Tensor::zeros(b.weight().shape(), dtype, device)?;
+                    *a = Linear::new(new_a, None);
+                    *b = Linear::new(new_b, None);
+                }
+            }
+            _ => unreachable!("Both adapters must be Either::Left or Either::Right."),
+        }
+        self.restack_adapters()?;
+        self.sync_adapter_store();
+        self.zero_grad();
+        Ok(())
+    }
+
+    /// Clear the accumulated gradients of the active adapters.
+    pub fn zero_grad(&mut self) {
+        self.grad_a.fill(None);
+        self.grad_b.fill(None);
+    }
+
+    /// Add to the accumulated gradient of the A weight of the active adapter at index `adapter`.
+    pub fn accumulate_grad_a(&mut self, adapter: usize, grad: Tensor) -> Result<()> {
+        let Some(a) = self.active_adapters().0.get(adapter) else {
+            bail!("No active adapter at index {adapter}.");
+        };
+        let weight = a.weight().clone();
+        accumulate_grad(&mut self.grad_a[adapter], &weight, grad)
+    }
+
+    /// Add to the accumulated gradient of the B weight of the active adapter at index `adapter`.
+    pub fn accumulate_grad_b(&mut self, adapter: usize, grad: Tensor) -> Result<()> {
+        let Some(b) = self.active_adapters().1.get(adapter) else {
+            bail!("No active adapter at index {adapter}.");
+        };
+        let weight = b.weight().clone();
+        accumulate_grad(&mut self.grad_b[adapter], &weight, grad)
+    }
+
+    /// Apply one SGD step, `w -= lr * grad`, to every adapter weight with an accumulated gradient.
+    /// Gradients are not cleared; call `zero_grad` before accumulating the next batch.
+    pub fn step(&mut self, lr: f64) -> Result<()> {
+        if self.merged || self.int8_adapters.is_some() {
+            bail!("Cannot train adapters which have been merged or quantized.");
+        }
+        match (&mut self.a_adapters, &mut self.b_adapters) {
+            (Either::Left(a), Either::Left(b)) | (Either::Right((_, a)), Either::Right((_, b))) => {
+                sgd_update(a, &self.grad_a, lr)?;
+                sgd_update(b, &self.grad_b, lr)?;
+            }
+            _ => unreachable!("Both adapters must be Either::Left or Either::Right."),
+        }
+        self.restack_adapters()?;
+        self.sync_adapter_store();
+        #[cfg(debug_assertions)]
+        self.assert_no_nan().map_err(candle_core::Error::wrap)?;
+        Ok(())
+    }
+
+    /// Check the active adapters' A and B weights for NaN or infinite values, which training can produce.
+    /// The error is for the first bad weight.
+    pub fn assert_no_nan(&self) -> std::result::Result<(), NanError> {
+        let (a_adapters, b_adapters) = self.active_adapters();
+        for (name, (a, b)) in zip(&self.adapter_names, zip(a_adapters, b_adapters)) {
+            for (matrix, w) in [('A', a.weight()), ('B', b.weight())] {
+                let n_nan = count_non_finite(w);
+                if n_nan > 0 {
+                    return Err(NanError {
+                        layer: self.layer_n,
+                        adapter_name: name.clone(),
+                        matrix,
+                        n_nan,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl AdapterSwapper for LoraLinear {
     fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+        if self.int8_adapters.is_some() {
+            bail!("Cannot activate adapters after they have been quantized.");
+        }
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -144,6 +1237,7 @@ impl AdapterSwapper for LoraLinear {
                         a: a_w,
                         b: b_w,
                         scale,
+                        ..
                     } = match self.adapters.get(adapter_name) {
                         Some(a) => a,
                         None => bail!("Cannot load adapter `{adapter_name}`."),
@@ -155,7 +1249,11 @@ impl AdapterSwapper for LoraLinear {
             }
             _ => unreachable!("Adapters should not be stacked if new ones are being activated."),
         }
-        Ok(())
+        self.adapter_names = adapter_names.to_vec();
+        self.grad_a = vec![None; adapter_names.len()];
+        self.grad_b = vec![None; adapter_names.len()];
+        self.check_weight_integrity()
+            .map_err(candle_core::Error::msg)
     }
     fn can_load(&self) -> bool {
         true
@@ -164,6 +1262,10 @@ impl AdapterSwapper for LoraLinear {
 
 impl Merge for LoraLinear {
     fn get_delta_weight(&self, adapter: usize) -> Result<Tensor> {
+        if let Some(int8_adapters) = &self.int8_adapters {
+            let (q_a, q_b) = &int8_adapters[adapter];
+            return q_b.dequantize()?.matmul(&q_a.dequantize()?)? * self.scale_adapters[adapter];
+        }
         match (&self.a_adapters, &self.b_adapters) {
             (Either::Left(a), Either::Left(b)) | (Either::Right((_, a)), Either::Right((_, b))) => {
                 let w_a = a[adapter].weight();
@@ -218,77 +1320,375 @@ impl LinearLayerLike for LoraLinear {
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
     ) -> Result<Tensor> {
-        let mut result = self.old.forward(input)?;
+        let result = self.base_forward(input)?;
 
-        if self.merged {
+        if is_scaling_pass.is_some_and(|x| x == 0.) {
             return Ok(result);
         }
 
-        if is_scaling_pass.is_some_and(|x| x == 0.) {
-            return Ok(result);
+        match self.adapter_delta(input, scalings, global_scaling_weight)? {
+            Some(delta) => delta.to_dtype(result.dtype())? + result,
+            None => Ok(result),
         }
+    }
+}
 
-        let scalings =
-            scalings.map(|scalings| get_maybe_topk_scalings(scalings, self.layer_n).unwrap());
-        if self.a_adapters.is_left()
-            || scalings
-                .as_ref()
-                .is_some_and(|scalings| scalings.dims3().unwrap().1 != 1)
-        {
-            let a_adapters = if self.a_adapters.is_right() {
-                self.a_adapters.as_ref().unwrap_right().1.clone()
-            } else {
-                self.a_adapters.as_ref().unwrap_left().clone()
-            };
-            let b_adapters = if self.b_adapters.is_right() {
-                self.b_adapters.as_ref().unwrap_right().1.clone()
-            } else {
-                self.b_adapters.as_ref().unwrap_left().clone()
-            };
-            //No fan_in_fan_out so no weight.transpose(0,1)
-            for (i, (adapter_a, (adapter_b, adapter_scale))) in
-                zip(a_adapters, zip(b_adapters, &self.scale_adapters)).enumerate()
-            {
-                let input_new = input.to_dtype(adapter_a.weight().dtype())?;
-                let input_new = if let Some(scalings) = &scalings {
-                    apply_scalings_to_x(input_new, scalings, i)?
-                } else {
-                    input_new
-                };
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
 
-                let res = adapter_b
-                    .forward(&adapter_a.forward(&input_new)?)?
-                    .mul(*adapter_scale)?
-                    .mul(global_scaling_weight)?;
-                result = (result + res)?;
-            }
-            Ok(result)
-        } else {
-            let adapter_a = &self.a_adapters.as_ref().unwrap_right().0;
-            let adapter_b = &self.b_adapters.as_ref().unwrap_right().0;
-            let adapter_scales = &self.scale_adapters;
-            let n_adapters = adapter_scales.len();
-            let adapter_a = if let Some(scalings) = scalings.as_ref() {
-                let scalings = scalings
-                    .squeeze(0)?
-                    .squeeze(0)?
-                    .unsqueeze(1)?
-                    .unsqueeze(1)?;
-                adapter_a
-                    .broadcast_mul(&scalings)?
-                    .mul(global_scaling_weight)?
-            } else {
-                adapter_a.clone().mul(global_scaling_weight)?
-            };
+    use candle_core::{DType, Device, Module, Result, Tensor};
+    use candle_nn::{Linear, VarBuilder};
 
-            let (b, s, h) = input.dims3()?;
-            let input = input.reshape((b * s, h))?;
-            let out = adapter_a.broadcast_matmul(&input.t()?)?;
-            let out = adapter_b.broadcast_matmul(&out)?;
-            let o_h = out.dims()[1];
-            let out = out.reshape((n_adapters, b, s, o_h))?;
-            let out = out.sum(0)?;
-            out + result
+    use super::{LoraLinear, WeightIntegrityError};
+    use crate::layers::QLinear;
+    use crate::lora::{LinearLayerLike, LoraConfig, LoraLinearConfig};
+
+    fn rank_2_config(dropout: Option<f32>) -> LoraConfig {
+        LoraConfig {
+            rank: 2,
+            alpha: 2.,
+            dropout,
+            target_modules: HashSet::new(),
+        }
+    }
+
+    /// One rank 2 adapter `adapter` with scale 1 over a 4x4 identity base.
+    fn identity_lora(a: Tensor, b: Tensor, dropout: Option<f32>) -> Result<LoraLinear> {
+        identity_lora_with(vec![("adapter", a, b)], dropout, 0, None)
+    }
+
+    /// `identity_lora` with the given `(name, A, B)` adapters, layer and B tying.
+    fn identity_lora_with(
+        adapters: Vec<(&str, Tensor, Tensor)>,
+        dropout: Option<f32>,
+        layer_n: usize,
+        tie_b_with: Option<Arc<LoraLinear>>,
+    ) -> Result<LoraLinear> {
+        let dev = Device::Cpu;
+        let base = Linear::new(Tensor::eye(4, DType::F32, &dev)?, None);
+        let mut ws = HashMap::new();
+        let mut config = Vec::new();
+        for (i, (name, a, b)) in adapters.into_iter().enumerate() {
+            ws.insert(format!("lora_A.{i}.weight"), a);
+            ws.insert(format!("lora_B.{i}.weight"), b);
+            config.push(((i.to_string(), name.to_string()), rank_2_config(dropout)));
+        }
+        let vb = VarBuilder::from_tensors(ws, DType::F32, &dev);
+        LoraLinear::new(
+            &base,
+            &LoraLinearConfig::new(4, 4),
+            &config,
+            &vb,
+            layer_n,
+            &None,
+            tie_b_with,
+        )
+    }
+
+    fn values(t: &Tensor) -> Result<Vec<f32>> {
+        t.flatten_all()?.to_vec1::<f32>()
+    }
+
+    #[test]
+    fn scale_zero_disables_adapter() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut lora = identity_lora(
+            Tensor::ones((2, 4), DType::F32, &dev)?,
+            Tensor::ones((4, 2), DType::F32, &dev)?,
+            None,
+        )?;
+
+        let x = Tensor::ones((1, 1, 4), DType::F32, &dev)?;
+        assert_ne!(
+            values(&lora.lora_forward(&x, None, 1., None)?)?,
+            vec![1.; 4]
+        );
+
+        lora.set_scale_for_adapter("adapter", 0.)?;
+        assert_eq!(
+            values(&lora.lora_forward(&x, None, 1., None)?)?,
+            vec![1.; 4]
+        );
+
+        assert!(lora.set_scale_for_adapter("missing", 1.).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn int8_adapters_match_full_precision() -> Result<()> {
+        let dev = Device::Cpu;
+        let a = Tensor::arange(0f32, 8., &dev)?.reshape((2, 4))?;
+        let b = Tensor::arange(-4f32, 4., &dev)?.reshape((4, 2))?;
+        let mut lora = identity_lora(a, b, None)?;
+        let x = Tensor::arange(0f32, 4., &dev)?.reshape((1, 1, 4))?;
+        let expected = values(&lora.lora_forward(&x, None, 1., None)?)?;
+
+        let stats = lora.quantize_adapters_int8()?;
+        assert_eq!(stats.n_tensors, 2);
+        assert_eq!(stats.original_bytes, 16 * 4);
+        // One byte per weight, plus an f32 scale for each of the 2 rows of A and 4 rows of B.
+        assert_eq!(stats.quantized_bytes, 16 + 6 * 4);
+
+        let got = values(&lora.lora_forward(&x, None, 1., None)?)?;
+        for (got, expected) in got.iter().zip(&expected) {
+            assert!(
+                (got - expected).abs() <= 0.01 * expected.abs(),
+                "{got} != {expected}"
+            );
         }
+
+        assert!(lora.quantize_adapters_int8().is_err());
+        assert!(lora.step(0.1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sgd_step_applies_accumulated_grads() -> Result<()> {
+        let dev = Device::Cpu;
+        let a = Tensor::ones((2, 4), DType::F32, &dev)?;
+        let b = Tensor::zeros((4, 2), DType::F32, &dev)?;
+        let mut lora = identity_lora(a, b, None)?;
+
+        lora.accumulate_grad_b(0, Tensor::ones((4, 2), DType::F32, &dev)?)?;
+        lora.accumulate_grad_b(0, Tensor::ones((4, 2), DType::F32, &dev)?)?;
+        assert!(lora
+            .accumulate_grad_a(0, Tensor::ones((4, 2), DType::F32, &dev)?)
+            .is_err());
+        assert!(lora
+            .accumulate_grad_b(1, Tensor::ones((4, 2), DType::F32, &dev)?)
+            .is_err());
+        lora.step(0.5)?;
+
+        // B is now -1 everywhere and A is unchanged, so the adapter subtracts 2 * sum(x).
+        let x = Tensor::arange(0f32, 4., &dev)?.reshape((1, 1, 4))?;
+        let expected = vec![-12f32, -11., -10., -9.];
+        assert_eq!(values(&lora.lora_forward(&x, None, 1., None)?)?, expected);
+
+        lora.zero_grad();
+        lora.step(0.5)?;
+        assert_eq!(values(&lora.lora_forward(&x, None, 1., None)?)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn reinit_adapters_restores_base_output() -> Result<()> {
+        let dev = Device::Cpu;
+        let a = Tensor::ones((2, 4), DType::F32, &dev)?;
+        let b = Tensor::ones((4, 2), DType::F32, &dev)?;
+        let mut lora = identity_lora(a, b, None)?;
+        let x = Tensor::arange(0f32, 4., &dev)?.reshape((1, 1, 4))?;
+        let base = values(&lora.base_forward(&x)?)?;
+        assert_ne!(values(&lora.lora_forward(&x, None, 1., None)?)?, base);
+
+        lora.reinit_adapters(&dev, DType::F32)?;
+        assert_eq!(values(&lora.lora_forward(&x, None, 1., None)?)?, base);
+
+        // Interpolating an adapter with itself reads back its stored weights.
+        let (a, b) = lora.interpolate_adapters("adapter", "adapter", 1.)?;
+        assert_eq!(a.dims(), &[2, 4]);
+        assert!(values(&a)?.iter().any(|&w| w != 0.));
+        assert_eq!(values(&b)?, vec![0.; 8]);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_multiple_adds_every_delta() -> Result<()> {
+        let dev = Device::Cpu;
+        let base = QLinear::from_parts(Tensor::eye(4, DType::F32, &dev)?, None);
+        let adapters = vec![
+            (
+                "one",
+                Tensor::ones((2, 4), DType::F32, &dev)?,
+                Tensor::ones((4, 2), DType::F32, &dev)?,
+                0.5,
+            ),
+            (
+                "two",
+                Tensor::ones((1, 4), DType::F32, &dev)?,
+                Tensor::arange(0f32, 4., &dev)?.reshape((4, 1))?,
+                1.,
+            ),
+        ];
+        let merged = LoraLinear::merge_multiple(base, adapters)?;
+
+        // Row `i` of the merged weight is the identity row plus `1 + i` everywhere.
+        let x = Tensor::arange(0f32, 4., &dev)?.reshape((1, 1, 4))?;
+        assert_eq!(values(&merged.forward(&x)?)?, vec![6., 13., 20., 27.]);
+
+        let base = QLinear::from_parts(Tensor::eye(4, DType::F32, &dev)?, None);
+        let mismatched = vec![(
+            "bad",
+            Tensor::ones((2, 3), DType::F32, &dev)?,
+            Tensor::ones((4, 2), DType::F32, &dev)?,
+            1.,
+        )];
+        assert!(LoraLinear::merge_multiple(base, mismatched).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn weight_integrity_checks_dropout_count() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut lora = identity_lora(
+            Tensor::zeros((2, 4), DType::F32, &dev)?,
+            Tensor::zeros((4, 2), DType::F32, &dev)?,
+            Some(0.1),
+        )?;
+        assert!(lora.check_weight_integrity().is_ok());
+
+        lora.adapters.clear();
+        assert!(matches!(
+            lora.check_weight_integrity(),
+            Err(WeightIntegrityError::DropoutCount {
+                adapters: 1,
+                dropouts: 0
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn assert_no_nan_names_layer_and_adapter() -> Result<()> {
+        let dev = Device::Cpu;
+        let a = Tensor::ones((2, 4), DType::F32, &dev)?;
+        let b = Tensor::new(&[[1f32, f32::NAN]; 4], &dev)?;
+        let lora = identity_lora_with(vec![("adapter", a, b)], None, 3, None)?;
+
+        let err = lora.assert_no_nan().unwrap_err();
+        assert_eq!(err.layer, 3);
+        assert_eq!(err.adapter_name, "adapter");
+        assert_eq!(err.matrix, 'B');
+        assert_eq!(err.n_nan, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn mc_dropout_variance_comes_from_dropout() -> Result<()> {
+        let dev = Device::Cpu;
+        let x = Tensor::arange(0f32, 4., &dev)?.reshape((1, 1, 4))?;
+        // (batch, seq, layers, adapters)
+        let scalings = Tensor::ones((1, 1, 1, 1), DType::F32, &dev)?;
+        let make = |dropout| {
+            identity_lora(
+                Tensor::ones((2, 4), DType::F32, &dev)?,
+                Tensor::ones((4, 2), DType::F32, &dev)?,
+                dropout,
+            )
+        };
+
+        let lora = make(None)?;
+        let (mean, variance) = lora.mc_dropout_forward(&x, scalings.clone(), 1., 4)?;
+        assert_eq!(
+            values(&mean)?,
+            values(&lora.lora_forward(&x, None, 1., None)?)?
+        );
+        assert_eq!(values(&variance)?, vec![0.; 4]);
+        assert!(lora
+            .mc_dropout_forward(&x, scalings.clone(), 1., 0)
+            .is_err());
+
+        let lora = make(Some(0.5))?;
+        let (_, variance) = lora.mc_dropout_forward(&x, scalings, 1., 32)?;
+        assert!(values(&variance)?.iter().all(|&v| v > 0.));
+        Ok(())
+    }
+
+    #[test]
+    fn shared_adapters_apply_to_every_layer() -> Result<()> {
+        let dev = Device::Cpu;
+        let eye = Linear::new(Tensor::eye(4, DType::F32, &dev)?, None);
+        let double = Linear::new((Tensor::eye(4, DType::F32, &dev)? * 2.)?, None);
+        let mut layers = LoraLinear::from_shared_adapters(
+            vec![&eye as &dyn LinearLayerLike, &double],
+            Tensor::ones((2, 4), DType::F32, &dev)?,
+            Tensor::ones((4, 2), DType::F32, &dev)?,
+            0.5,
+            0,
+        )?;
+
+        let x = Tensor::arange(0f32, 4., &dev)?.reshape((1, 1, 4))?;
+        assert_eq!(
+            values(&layers[0].lora_forward(&x, None, 1., None)?)?,
+            vec![6., 7., 8., 9.]
+        );
+        assert_eq!(
+            values(&layers[1].lora_forward(&x, None, 1., None)?)?,
+            vec![6., 8., 10., 12.]
+        );
+
+        // Training one layer leaves the others on the shared weights.
+        layers[0].accumulate_grad_b(0, Tensor::ones((4, 2), DType::F32, &dev)?)?;
+        layers[0].step(1.)?;
+        assert_eq!(
+            values(&layers[0].lora_forward(&x, None, 1., None)?)?,
+            vec![0., 1., 2., 3.]
+        );
+        assert_eq!(
+            values(&layers[1].lora_forward(&x, None, 1., None)?)?,
+            vec![6., 8., 10., 12.]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn interpolated_adapter_blends_weights() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut lora = identity_lora_with(
+            vec![
+                (
+                    "first",
+                    Tensor::ones((2, 4), DType::F32, &dev)?,
+                    Tensor::ones((4, 2), DType::F32, &dev)?,
+                ),
+                (
+                    "second",
+                    Tensor::full(2f32, (2, 4), &dev)?,
+                    Tensor::zeros((4, 2), DType::F32, &dev)?,
+                ),
+            ],
+            None,
+            0,
+            None,
+        )?;
+
+        let (a, b) = lora.interpolate_adapters("first", "second", 0.25)?;
+        assert_eq!(values(&a)?, vec![1.75; 8]);
+        assert_eq!(values(&b)?, vec![0.25; 8]);
+
+        lora.create_interpolated_adapter("first", "second", 0.25, "blend")?;
+        let (a, b) = lora.interpolate_adapters("blend", "blend", 1.)?;
+        assert_eq!(values(&a)?, vec![1.75; 8]);
+        assert_eq!(values(&b)?, vec![0.25; 8]);
+
+        assert!(lora
+            .create_interpolated_adapter("first", "second", 0.5, "blend")
+            .is_err());
+        assert!(lora.interpolate_adapters("first", "missing", 0.5).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tied_b_matches_untied() -> Result<()> {
+        let dev = Device::Cpu;
+        let make = |b: Tensor, tie_b_with: Option<Arc<LoraLinear>>| {
+            let a = Tensor::ones((2, 4), DType::F32, &dev)?;
+            identity_lora_with(vec![("adapter", a, b)], None, 0, tie_b_with)
+        };
+        let b = Tensor::arange(0f32, 8., &dev)?.reshape((4, 2))?;
+        let source = Arc::new(make(b.clone(), None)?);
+        let untied = make(b, None)?;
+        let tied = make(Tensor::zeros((4, 2), DType::F32, &dev)?, Some(source))?;
+
+        let x = Tensor::arange(0f32, 4., &dev)?.reshape((1, 1, 4))?;
+        assert_eq!(
+            values(&tied.lora_forward(&x, None, 1., None)?)?,
+            values(&untied.lora_forward(&x, None, 1., None)?)?
+        );
+
+        Ok(())
     }
 }
@@ -7,7 +7,10 @@ use candle_core::{
     IndexOp, Result, Tensor, D,
 };
 use candle_nn::{init, Linear, Module, VarBuilder};
-use loralinear::LoraLinear;
+pub use loralinear::{
+    AdapterTiming, BatchingRecommendation, LoraLinear, NanError, OverheadStats,
+    QuantizedAdapterStats, WeightIntegrityError,
+};
 pub use qloralinear::QLoraLinear;
 use serde::Deserialize;
 
@@ -70,6 +73,7 @@ struct Adapter {
     a: Linear,
     b: Linear,
     scale: f64,
+    dropout: f32, // LoRA dropout on the adapter input, only used by `LoraLinear::mc_dropout_forward`.
 }
 
 fn make_adapter(
@@ -93,7 +97,12 @@ fn make_adapter(
     } else {
         1.0
     };
-    Ok(Adapter { a, b, scale })
+    Ok(Adapter {
+        a,
+        b,
+        scale,
+        dropout: cfg.dropout.unwrap_or(0.),
+    })
 }
 
 /// Any layer that is linear-like.
@@ -220,6 +229,7 @@ pub fn linear(
         &vb,
         layer,
         preload_adapters,
+        None,
     )?;
     *count += 1;
     Ok(Arc::new(lorainner))
@@ -272,6 +282,7 @@ pub fn linear_no_bias(
         &vb,
         layer,
         preload_adapters,
+        None,
     )?;
     *count += 1;
     Ok(Arc::new(lorainner))
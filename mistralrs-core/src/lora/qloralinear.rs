@@ -182,6 +182,7 @@ impl AdapterSwapper for QLoraLinear {
                         a: a_w,
                         b: b_w,
                         scale,
+                        ..
                     } = match self.adapters.get(adapter_name) {
                         Some(a) => a,
                         None => bail!("Cannot load adapter `{adapter_name}`."),
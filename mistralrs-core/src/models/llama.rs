@@ -10,7 +10,9 @@ use std::sync::Arc;
 use crate::{
     device_map::DeviceMapper,
     layers::{repeat_kv, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention},
-    pipeline::{extract_logits, IsqModel, NormalLoadingMetadata, NormalModel},
+    pipeline::{
+        extract_logits, select_hidden_states, IsqModel, NormalLoadingMetadata, NormalModel,
+    },
 };
 
 #[derive(Debug, Clone, Deserialize)]
@@ -257,18 +259,46 @@ impl Llama {
     pub fn forward(
         &mut self,
         input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
+        let (x, _) = self.forward_layers(
+            input_ids,
+            padding_mask,
+            seqlen_offsets,
+            start_offsets_kernel,
+            &[],
+        )?;
+        let x = x.to_device(&self.device)?;
+        let mut x = self.ln_f.forward(&x)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            x = x.to_dtype(DType::F32)?;
+        }
+        let logits = MatMul.qmatmul(&x, &self.lm_head)?;
+        extract_logits(&logits, context_lens)
+    }
+
+    /// Run the decoder layers, also returning the output of each layer in `hidden_layers`.
+    fn forward_layers(
+        &mut self,
+        input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        hidden_layers: &[usize],
+    ) -> Result<(Tensor, Vec<(usize, Tensor)>)> {
         let mut x = self.wte.forward(input_ids)?;
         let mut cache = self.kv_cache.lock();
-        let mask = CausalMasker.make_causal_mask_as_attn_bias(
+        let mask = CausalMasker.make_causal_mask_with_padding_as_attn_bias(
             input_ids,
             &cache,
+            padding_mask,
             x.dtype(),
             self.blocks[0].attn.num_attention_heads,
         )?;
+        let mut hidden_states = Vec::new();
         for (block_idx, block) in self.blocks.iter().enumerate() {
             x = self.mapper.map(x, block_idx)?;
             x = block.forward(
@@ -279,14 +309,11 @@ impl Llama {
                 block_idx,
                 &mut cache,
             )?;
+            if hidden_layers.contains(&block_idx) {
+                hidden_states.push((block_idx, x.to_device(&self.device)?));
+            }
         }
-        let x = x.to_device(&self.device)?;
-        let mut x = self.ln_f.forward(&x)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            x = x.to_dtype(DType::F32)?;
-        }
-        let logits = MatMul.qmatmul(&x, &self.lm_head)?;
-        extract_logits(&logits, context_lens)
+        Ok((x, hidden_states))
     }
 
     pub fn new(
@@ -374,6 +401,7 @@ impl NormalModel for Llama {
     fn forward(
         &mut self,
         input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
@@ -381,11 +409,29 @@ impl NormalModel for Llama {
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
+            padding_mask,
             seqlen_offsets,
             start_offsets_kernel,
             context_lens,
         )
     }
+    fn hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        layers: &[usize],
+    ) -> Result<Vec<Tensor>> {
+        let (_, hidden_states) = self.forward_layers(
+            input_ids,
+            None,
+            seqlen_offsets,
+            start_offsets_kernel,
+            layers,
+        )?;
+        select_hidden_states(hidden_states, layers)
+    }
     fn xlora_forward(
         &mut self,
         _input_ids: &Tensor,
@@ -11,7 +11,9 @@ use std::sync::Arc;
 use crate::{
     device_map::DeviceMapper,
     layers::{repeat_kv, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention},
-    pipeline::{extract_logits, Cache, IsqModel, NormalLoadingMetadata, NormalModel},
+    pipeline::{
+        extract_logits, select_hidden_states, Cache, IsqModel, NormalLoadingMetadata, NormalModel,
+    },
 };
 
 /// https://github.com/huggingface/transformers/blob/1a585c1222a56bcaecc070966d558d4a9d862e83/src/transformers/models/mixtral/configuration_mixtral.py#L113
@@ -443,19 +445,47 @@ impl Model {
     pub fn forward(
         &mut self,
         input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
-        let mut xs = self.embed_tokens.forward(input_ids)?;
-        let mut cache = self.cache.lock();
-        let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
+        let (xs, _) = self.forward_layers(
             input_ids,
-            &cache,
-            Some(self.sliding_window),
-            xs.dtype(),
-            self.layers[0].self_attn.num_heads,
+            padding_mask,
+            seqlen_offsets,
+            start_offsets_kernel,
+            &[],
         )?;
+        let xs = xs.to_device(&self.device)?;
+        let mut xs = xs.apply(&self.norm)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+    }
+
+    /// Run the decoder layers, also returning the output of each layer in `hidden_layers`.
+    fn forward_layers(
+        &mut self,
+        input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        hidden_layers: &[usize],
+    ) -> Result<(Tensor, Vec<(usize, Tensor)>)> {
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        let mut cache = self.cache.lock();
+        let attention_mask = CausalMasker
+            .make_causal_mask_with_sliding_window_and_padding_as_attn_bias(
+                input_ids,
+                &cache,
+                Some(self.sliding_window),
+                padding_mask,
+                xs.dtype(),
+                self.layers[0].self_attn.num_heads,
+            )?;
+        let mut hidden_states = Vec::new();
         for (i, layer) in self.layers.iter_mut().enumerate() {
             xs = self.mapper.map(xs, i)?;
             xs = layer.forward(
@@ -468,13 +498,11 @@ impl Model {
                 start_offsets_kernel.clone(),
                 &mut cache[i],
             )?;
+            if hidden_layers.contains(&i) {
+                hidden_states.push((i, xs.to_device(&self.device)?));
+            }
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+        Ok((xs, hidden_states))
     }
 }
 
@@ -502,6 +530,7 @@ impl NormalModel for Model {
     fn forward(
         &mut self,
         input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
@@ -509,11 +538,29 @@ impl NormalModel for Model {
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
+            padding_mask,
             seqlen_offsets,
             start_offsets_kernel,
             context_lens,
         )
     }
+    fn hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        layers: &[usize],
+    ) -> Result<Vec<Tensor>> {
+        let (_, hidden_states) = self.forward_layers(
+            input_ids,
+            None,
+            seqlen_offsets,
+            start_offsets_kernel,
+            layers,
+        )?;
+        select_hidden_states(hidden_states, layers)
+    }
     fn xlora_forward(
         &mut self,
         _input_ids: &Tensor,
@@ -14,7 +14,9 @@ use serde::Deserialize;
 use crate::{
     device_map::DeviceMapper,
     layers::{repeat_kv, CausalMasker, QLinear, ScaledDotProductAttention},
-    pipeline::{extract_logits, Cache, IsqModel, NormalLoadingMetadata, NormalModel},
+    pipeline::{
+        extract_logits, select_hidden_states, Cache, IsqModel, NormalLoadingMetadata, NormalModel,
+    },
 };
 
 // https://huggingface.co/microsoft/phi-2/blob/main/configuration_phi.py
@@ -347,18 +349,45 @@ impl Model {
     pub fn forward(
         &mut self,
         input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
+        let (xs, _) = self.forward_layers(
+            input_ids,
+            padding_mask,
+            seqlen_offsets,
+            start_offsets_kernel,
+            &[],
+        )?;
+        let xs = xs.to_device(&self.device)?;
+        let mut xs = xs.apply(&self.final_layernorm)?;
+        if self.lm_head.is_quant() {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&xs.apply(&self.lm_head)?, context_lens)
+    }
+
+    /// Run the decoder layers, also returning the output of each layer in `hidden_layers`.
+    fn forward_layers(
+        &mut self,
+        input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        hidden_layers: &[usize],
+    ) -> Result<(Tensor, Vec<(usize, Tensor)>)> {
         let mut xs = input_ids.apply(&self.embed_tokens)?;
         let mut cache = self.cache.lock();
-        let mask = CausalMasker.make_causal_mask_as_attn_bias(
+        let mask = CausalMasker.make_causal_mask_with_padding_as_attn_bias(
             input_ids,
             &cache,
+            padding_mask,
             xs.dtype(),
             self.layers[0].self_attn.num_heads,
         )?;
+        let mut hidden_states = Vec::new();
         for (i, layer) in self.layers.iter_mut().enumerate() {
             xs = self.mapper.map(xs, i)?;
             xs = layer.forward(
@@ -370,13 +399,11 @@ impl Model {
                 start_offsets_kernel.clone(),
                 &mut cache[i],
             )?;
+            if hidden_layers.contains(&i) {
+                hidden_states.push((i, xs.to_device(&self.device)?));
+            }
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.final_layernorm)?;
-        if self.lm_head.is_quant() {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-        extract_logits(&xs.apply(&self.lm_head)?, context_lens)
+        Ok((xs, hidden_states))
     }
 }
 
@@ -400,6 +427,7 @@ impl NormalModel for Model {
     fn forward(
         &mut self,
         input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
@@ -407,11 +435,29 @@ impl NormalModel for Model {
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
+            padding_mask,
             seqlen_offsets,
             start_offsets_kernel,
             context_lens,
         )
     }
+    fn hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        layers: &[usize],
+    ) -> Result<Vec<Tensor>> {
+        let (_, hidden_states) = self.forward_layers(
+            input_ids,
+            None,
+            seqlen_offsets,
+            start_offsets_kernel,
+            layers,
+        )?;
+        select_hidden_states(hidden_states, layers)
+    }
     fn xlora_forward(
         &mut self,
         _input_ids: &Tensor,
@@ -13,7 +13,8 @@ use crate::{
         ScaledDotProductAttention,
     },
     pipeline::{
-        extract_logits, Cache, IsqModel, NormalLoadingMetadata, NormalModel, Phi3RopeScaling,
+        extract_logits, select_hidden_states, Cache, IsqModel, NormalLoadingMetadata, NormalModel,
+        Phi3RopeScaling,
     },
 };
 
@@ -348,20 +349,43 @@ impl Model {
     pub fn forward(
         &mut self,
         input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         position_ids: &[usize],
         context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
+        let (xs, _) =
+            self.forward_layers(input_ids, padding_mask, seqlen_offsets, position_ids, &[])?;
+        let xs = xs.to_device(&self.device)?;
+        let mut xs = xs.apply(&self.norm)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+    }
+
+    /// Run the decoder layers, also returning the output of each layer in `hidden_layers`.
+    fn forward_layers(
+        &mut self,
+        input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        position_ids: &[usize],
+        hidden_layers: &[usize],
+    ) -> Result<(Tensor, Vec<(usize, Tensor)>)> {
         let mut xs = self.embed_tokens.forward(input_ids)?;
         let mut cache = self.cache.lock();
-        let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
-            input_ids,
-            &cache,
-            self.sliding_window,
-            xs.dtype(),
-            self.layers[0].self_attn.num_heads,
-        )?;
+        let attention_mask = CausalMasker
+            .make_causal_mask_with_sliding_window_and_padding_as_attn_bias(
+                input_ids,
+                &cache,
+                self.sliding_window,
+                padding_mask,
+                xs.dtype(),
+                self.layers[0].self_attn.num_heads,
+            )?;
 
+        let mut hidden_states = Vec::new();
         for (i, layer) in self.layers.iter_mut().enumerate() {
             xs = self.mapper.map(xs, i)?;
             xs = layer.forward(
@@ -373,14 +397,12 @@ impl Model {
                 seqlen_offsets,
                 position_ids,
                 &mut cache[i],
-            )?
-        }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            xs = xs.to_dtype(DType::F32)?;
+            )?;
+            if hidden_layers.contains(&i) {
+                hidden_states.push((i, xs.to_device(&self.device)?));
+            }
         }
-        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+        Ok((xs, hidden_states))
     }
 }
 
@@ -402,12 +424,31 @@ impl NormalModel for Model {
     fn forward(
         &mut self,
         input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         _start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
         position_ids: Vec<usize>,
     ) -> Result<Tensor> {
-        self.forward(input_ids, seqlen_offsets, &position_ids, context_lens)
+        self.forward(
+            input_ids,
+            padding_mask,
+            seqlen_offsets,
+            &position_ids,
+            context_lens,
+        )
+    }
+    fn hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        position_ids: Vec<usize>,
+        layers: &[usize],
+    ) -> Result<Vec<Tensor>> {
+        let (_, hidden_states) =
+            self.forward_layers(input_ids, None, seqlen_offsets, &position_ids, layers)?;
+        select_hidden_states(hidden_states, layers)
     }
     fn xlora_forward(
         &mut self,
@@ -484,15 +484,17 @@ impl ModelWeights {
     pub fn forward(
         &mut self,
         x: &Tensor,
+        padding_mask: Option<&Tensor>,
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         let mut layer_in = self.tok_embeddings.forward(x)?;
         let mut cache = self.cache.lock();
-        let mask = CausalMasker.make_causal_mask_as_attn_bias(
+        let mask = CausalMasker.make_causal_mask_with_padding_as_attn_bias(
             x,
             &cache,
+            padding_mask,
             DType::F32,
             self.layers[0].n_head,
         )?;
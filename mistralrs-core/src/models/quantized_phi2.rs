@@ -268,14 +268,16 @@ impl ModelWeights {
     pub fn forward(
         &mut self,
         input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         let mut xs = self.tok_embeddings.forward(input_ids)?;
         let mut cache = self.cache.lock();
-        let mask = CausalMasker.make_causal_mask_as_attn_bias(
+        let mask = CausalMasker.make_causal_mask_with_padding_as_attn_bias(
             input_ids,
             &cache,
+            padding_mask,
             DType::F32,
             self.layers[0].n_head,
         )?;
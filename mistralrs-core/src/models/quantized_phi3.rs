@@ -301,14 +301,20 @@ impl ModelConfig::FromGGUF for ModelWeights {
 }
 
 impl ModelWeights {
-    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offsets: &[usize]) -> Result<Tensor> {
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+    ) -> Result<Tensor> {
         let (_b_sz, seq_len) = input_ids.dims2()?;
         let mut xs = self.tok_embeddings.forward(input_ids)?;
         let mut cache = self.cache.lock();
-        let mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
+        let mask = CausalMasker.make_causal_mask_with_sliding_window_and_padding_as_attn_bias(
             input_ids,
             &cache,
             Some(self.max_seq_len),
+            padding_mask,
             DType::F32,
             self.layers[0].n_head,
         )?;
@@ -128,17 +128,11 @@ impl Cache {
                             kv_seq_len - (sliding_window - 1),
                             sliding_window - 1,
                         )?;
+                        // Keep the bias of the remaining cached keys and of the new ones.
                         if let Some(ref mut mask) = mask {
-                            let mask_len = mask.dim(1)?;
-                            *mask = mask.narrow(
-                                1,
-                                mask_len - (sliding_window - 1),
-                                sliding_window - 1,
-                            )?;
-                            *mask = Tensor::cat(
-                                &[&*mask, &mask.narrow(1, mask_len - 1, 1)?.ones_like()?],
-                                D::Minus1,
-                            )?;
+                            let mask_len = mask.dim(D::Minus1)?;
+                            let kv_len = sliding_window - 1 + k.dim(2)?;
+                            *mask = mask.narrow(D::Minus1, mask_len - kv_len, kv_len)?;
                         }
                     }
                 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fs, path::Path};
 
 use anyhow::Result;
 use either::Either;
@@ -6,7 +6,7 @@ use indexmap::IndexMap;
 use minijinja::{context, Environment, ErrorKind};
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::MessageContent;
 
@@ -106,11 +106,7 @@ pub fn calculate_eos_tokens(
     }
 
     if let Some(gen_conf) = gen_conf {
-        let ids = match gen_conf.eos_token_id {
-            Either::Left(id) => vec![id],
-            Either::Right(ids) => ids,
-        };
-        for id in ids {
+        for id in gen_conf.eos_token_ids() {
             let s = tokenizer
                 .decode(&[id], false)
                 .unwrap_or_else(|_| panic!("Unable to decode id {id})"));
@@ -120,8 +116,9 @@ pub fn calculate_eos_tokens(
         }
 
         let ids = match gen_conf.bos_token_id {
-            Either::Left(id) => vec![id],
-            Either::Right(ids) => ids,
+            Some(Either::Left(id)) => vec![id],
+            Some(Either::Right(ids)) => ids,
+            None => vec![],
         };
         for id in ids {
             let s = tokenizer
@@ -159,16 +156,56 @@ pub fn calculate_eos_tokens(
                 .unwrap_or_else(|| panic!("Unable to extract `{eos_tok}` EOS token.")),
         )
     }
+    if eos_toks.is_empty() {
+        warn!("No EOS token was found in the chat template, tokenizer or generation config. Sequences will only stop at the length limits, stop tokens or stop strings.");
+    }
     eos_toks
 }
 
-#[allow(dead_code)]
+/// The default generation parameters from a model's `generation_config.json`. Missing fields take the
+/// HuggingFace Transformers defaults.
 #[derive(Debug, Deserialize)]
 pub struct GenerationConfig {
-    #[serde(with = "either::serde_untagged")]
-    bos_token_id: Either<u32, Vec<u32>>,
-    #[serde(with = "either::serde_untagged")]
-    eos_token_id: Either<u32, Vec<u32>>,
+    #[serde(default, with = "either::serde_untagged_optional")]
+    bos_token_id: Option<Either<u32, Vec<u32>>>,
+    #[serde(default, with = "either::serde_untagged_optional")]
+    eos_token_id: Option<Either<u32, Vec<u32>>>,
+    #[serde(default = "default_one")]
+    pub temperature: f64,
+    #[serde(default = "default_one")]
+    pub top_p: f64,
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    #[serde(default = "default_one")]
+    pub repetition_penalty: f64,
+    #[serde(default)]
+    pub max_new_tokens: Option<usize>,
+}
+
+fn default_one() -> f64 {
+    1.0
+}
+
+fn default_top_k() -> usize {
+    50
+}
+
+impl GenerationConfig {
+    pub fn eos_token_ids(&self) -> Vec<u32> {
+        match &self.eos_token_id {
+            Some(Either::Left(id)) => vec![*id],
+            Some(Either::Right(ids)) => ids.clone(),
+            None => vec![],
+        }
+    }
+}
+
+/// Parse `generation_config.json` in `model_dir`.
+pub fn load_generation_config(model_dir: &Path) -> Result<GenerationConfig> {
+    let path = model_dir.join("generation_config.json");
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| anyhow::anyhow!("Could not read `{}`: {e}", path.display()))?;
+    Ok(serde_json::from_str(&contents)?)
 }
 
 pub fn apply_chat_template_to(
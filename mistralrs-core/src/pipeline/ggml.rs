@@ -464,10 +464,15 @@ impl Pipeline for GGMLPipeline {
             seqlen_offsets_kernel_full,
             context_lens,
             position_ids: _, // NOTE(EricLBuehler): ignore, it is for phi3
+            attention_mask,
         } = *inputs.downcast().expect("Downcast failed.");
+        if attention_mask.is_some() && matches!(self.model, Model::XLoraLlama(_)) {
+            candle_core::bail!("Prompt attention masks are not supported for X-LoRA models.");
+        }
         match self.model {
             Model::Llama(ref mut model) => model.forward(
                 &input_ids,
+                attention_mask.as_ref(),
                 &seqlen_offsets,
                 seqlen_offsets_kernel,
                 context_lens,
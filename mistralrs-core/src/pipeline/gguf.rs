@@ -598,15 +598,27 @@ impl Pipeline for GGUFPipeline {
             seqlen_offsets_kernel_full,
             context_lens,
             position_ids: _, // NOTE(EricLBuehler): ignore, it is for phi3
+            attention_mask,
         } = *inputs.downcast().expect("Downcast failed.");
+        if attention_mask.is_some()
+            && matches!(self.model, Model::XLoraLlama(_) | Model::XLoraPhi3(_))
+        {
+            candle_core::bail!("Prompt attention masks are not supported for X-LoRA models.");
+        }
         match self.model {
             Model::Llama(ref mut model) => model.forward(
                 &input_ids,
+                attention_mask.as_ref(),
                 &seqlen_offsets,
                 seqlen_offsets_kernel,
                 context_lens,
             ),
-            Model::Phi2(ref mut model) => model.forward(&input_ids, &seqlen_offsets, context_lens),
+            Model::Phi2(ref mut model) => model.forward(
+                &input_ids,
+                attention_mask.as_ref(),
+                &seqlen_offsets,
+                context_lens,
+            ),
             Model::XLoraLlama(ref mut model) => model.forward(
                 &input_ids,
                 input_ids_full.as_ref().unwrap_or(&input_ids),
@@ -618,7 +630,9 @@ impl Pipeline for GGUFPipeline {
                 &self.non_granular_state,
                 context_lens,
             ),
-            Model::Phi3(ref mut model) => model.forward(&input_ids, &seqlen_offsets),
+            Model::Phi3(ref mut model) => {
+                model.forward(&input_ids, attention_mask.as_ref(), &seqlen_offsets)
+            }
             Model::XLoraPhi3(ref mut model) => model.forward(
                 &input_ids,
                 input_ids_full.as_ref().unwrap_or(&input_ids),
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+use indexmap::IndexMap;
+
+/// The hidden states computed by `Pipeline::compute_hidden_states`, by prompt and then by layer.
+///
+/// Entries are evicted least recently used first once there are more than `max_prompts` prompts.
+/// The cache must be cleared whenever the model weights change.
+pub(crate) struct HiddenStatesCache {
+    prompts: IndexMap<Vec<u32>, HashMap<usize, Tensor>>,
+    max_prompts: usize,
+}
+
+impl HiddenStatesCache {
+    pub(crate) fn new(max_prompts: usize) -> Self {
+        Self {
+            prompts: IndexMap::new(),
+            max_prompts,
+        }
+    }
+
+    /// The hidden states of `layers` for `tokens`, if all of them are cached. Marks the prompt as most recently used.
+    pub(crate) fn get(&mut self, tokens: &[u32], layers: &[usize]) -> Option<Vec<Tensor>> {
+        let (tokens, states) = self.prompts.shift_remove_entry(tokens)?;
+        let found = layers
+            .iter()
+            .map(|layer| states.get(layer).cloned())
+            .collect::<Option<Vec<_>>>();
+        self.prompts.insert(tokens, states);
+        found
+    }
+
+    /// Add the hidden states of `layers` for `tokens`, keeping any other layers already cached for them.
+    pub(crate) fn insert(&mut self, tokens: &[u32], layers: &[usize], hidden_states: &[Tensor]) {
+        let mut states = self.prompts.shift_remove(tokens).unwrap_or_default();
+        states.extend(layers.iter().copied().zip(hidden_states.iter().cloned()));
+        self.prompts.insert(tokens.to_vec(), states);
+        while self.prompts.len() > self.max_prompts {
+            self.prompts.shift_remove_index(0);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.prompts.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::HiddenStatesCache;
+
+    #[test]
+    fn hits_misses_and_eviction() {
+        let xs = Tensor::zeros((1, 2, 4), DType::F32, &Device::Cpu).unwrap();
+        let mut cache = HiddenStatesCache::new(2);
+        cache.insert(&[1, 2], &[0, 3], &[xs.clone(), xs.clone()]);
+        assert_eq!(cache.get(&[1, 2], &[3, 0]).map(|s| s.len()), Some(2));
+        assert!(cache.get(&[1, 2], &[1]).is_none());
+        assert!(cache.get(&[1], &[0]).is_none());
+
+        // A later query for more layers keeps those already cached.
+        cache.insert(&[1, 2], &[1], &[xs.clone()]);
+        assert!(cache.get(&[1, 2], &[0, 1, 3]).is_some());
+
+        // [1, 2] was just used, so [3] is evicted first.
+        cache.insert(&[3], &[0], &[xs.clone()]);
+        cache.get(&[1, 2], &[0]);
+        cache.insert(&[4], &[0], &[xs.clone()]);
+        assert!(cache.get(&[3], &[0]).is_none());
+        assert!(cache.get(&[1, 2], &[0]).is_some());
+
+        cache.clear();
+        assert!(cache.get(&[4], &[0]).is_none());
+    }
+}
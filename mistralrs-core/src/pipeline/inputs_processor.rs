@@ -55,6 +55,36 @@ pub mod text_models_inputs_processor {
         pub positions_kernel: Tensor,          // [bs, seq len]
         pub context_lens: Vec<(usize, usize)>, // (start index, len)
         pub position_ids: Vec<usize>,
+        pub attention_mask: Option<Tensor>, // [bs, kv len], 0 for padding
+    }
+
+    /// The keys each sequence may attend to, from the prompt attention masks and the padding up to `len`.
+    /// `None` if no sequence has a prompt attention mask.
+    fn get_attention_mask(
+        input_seqs: &[&mut Sequence],
+        len: usize,
+        device: &Device,
+    ) -> Result<Option<Tensor>> {
+        if input_seqs
+            .iter()
+            .all(|seq| seq.prompt_attention_mask().is_none())
+        {
+            return Ok(None);
+        }
+        let mut mask = Vec::with_capacity(input_seqs.len() * len);
+        for seq in input_seqs {
+            let prompt_mask = seq.prompt_attention_mask().unwrap_or_default();
+            mask.extend(
+                (0..len).map(|i| {
+                    u8::from(i < seq.len() && prompt_mask.get(i).copied().unwrap_or(true))
+                }),
+            );
+        }
+        Ok(Some(Tensor::from_vec(
+            mask,
+            (input_seqs.len(), len),
+            device,
+        )?))
     }
 
     pub(crate) fn get_prompt_input<T: WithDType>(
@@ -122,6 +152,7 @@ pub mod text_models_inputs_processor {
             positions_kernel,
             context_lens,
             position_ids,
+            attention_mask: get_attention_mask(input_seqs, max_len, device)?,
         })
     }
 
@@ -158,12 +189,14 @@ pub mod text_models_inputs_processor {
         }
         let positions_kernel = Tensor::cat(&tmp, 0)?;
         set_use_matmul_via_f16(false);
+        let max_len = input_seqs.iter().map(|seq| seq.len()).max().unwrap_or(0);
         Ok(InputMetadata {
             input: Tensor::cat(&seqs_tensors, 0).unwrap(),
             positions: seqlen_offsets,
             positions_kernel,
             context_lens,
             position_ids,
+            attention_mask: get_attention_mask(input_seqs, max_len, device)?,
         })
     }
 
@@ -177,6 +210,7 @@ pub mod text_models_inputs_processor {
         pub seqlen_offsets_kernel_full: Option<Tensor>,
         pub context_lens: Vec<(usize, usize)>,
         pub position_ids: Vec<usize>,
+        pub attention_mask: Option<Tensor>, // [bs, kv len], 0 for padding
     }
 
     pub struct TextInputsProcessor;
@@ -200,6 +234,7 @@ pub mod text_models_inputs_processor {
                     positions_kernel: seqlen_offsets_kernel_full,
                     context_lens: _,
                     position_ids,
+                    attention_mask: _,
                 } = get_prompt_input(
                     input_seqs
                         .iter()
@@ -215,6 +250,7 @@ pub mod text_models_inputs_processor {
                     positions_kernel: seqlen_offsets_kernel,
                     context_lens,
                     position_ids: _,
+                    attention_mask,
                 } = get_completion_input(
                     input_seqs
                         .iter()
@@ -234,6 +270,7 @@ pub mod text_models_inputs_processor {
                     seqlen_offsets_kernel_full: Some(seqlen_offsets_kernel_full),
                     context_lens,
                     position_ids,
+                    attention_mask,
                 }))
             } else if is_xlora && is_prompt {
                 let InputMetadata {
@@ -242,6 +279,7 @@ pub mod text_models_inputs_processor {
                     positions_kernel: seqlen_offsets_kernel,
                     context_lens,
                     position_ids,
+                    attention_mask,
                 } = get_prompt_input(
                     input_seqs
                         .iter()
@@ -260,6 +298,7 @@ pub mod text_models_inputs_processor {
                     seqlen_offsets_kernel_full: Some(seqlen_offsets_kernel),
                     context_lens,
                     position_ids,
+                    attention_mask,
                 }))
             } else if is_prompt {
                 let InputMetadata {
@@ -268,6 +307,7 @@ pub mod text_models_inputs_processor {
                     positions_kernel: seqlen_offsets_kernel,
                     context_lens,
                     position_ids,
+                    attention_mask,
                 } = get_prompt_input(
                     input_seqs
                         .iter()
@@ -286,6 +326,7 @@ pub mod text_models_inputs_processor {
                     seqlen_offsets_kernel_full: None,
                     context_lens,
                     position_ids,
+                    attention_mask,
                 }))
             } else {
                 let InputMetadata {
@@ -294,6 +335,7 @@ pub mod text_models_inputs_processor {
                     positions_kernel: seqlen_offsets_kernel,
                     context_lens,
                     position_ids,
+                    attention_mask,
                 } = get_completion_input(
                     input_seqs
                         .iter()
@@ -313,6 +355,7 @@ pub mod text_models_inputs_processor {
                     seqlen_offsets_kernel_full: None,
                     context_lens,
                     position_ids,
+                    attention_mask,
                 }))
             }
         }
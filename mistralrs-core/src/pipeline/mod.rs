@@ -2,6 +2,7 @@ mod cache_manager;
 pub mod chat_template;
 mod ggml;
 mod gguf;
+mod hidden_states;
 mod inputs_processor;
 mod isq;
 mod macros;
@@ -41,6 +42,7 @@ use std::sync::Arc;
 use std::{collections::HashMap, path::PathBuf, str::FromStr};
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
+use tracing::warn;
 pub use vision::{VisionLoader, VisionLoaderBuilder, VisionSpecificConfig};
 pub use vision_loaders::{Idefics2Loader, Phi3VLoader, VisionLoaderType, VisionModelLoader};
 
@@ -480,6 +482,35 @@ pub trait MetadataMixin {
     fn get_metadata(&self) -> &GeneralMetadata;
 }
 
+/// Which tokens [`Pipeline::truncate_prompt`] drops.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TruncationStrategy {
+    /// Drop the oldest tokens, keeping the end of the prompt.
+    Left,
+    /// Drop the newest tokens, keeping the start of the prompt.
+    Right,
+    /// Drop tokens from the middle, keeping this fraction of the remaining space for the start of the prompt.
+    Middle(f32),
+}
+
+/// Returned by [`Pipeline::truncate_prompt`] when it dropped tokens.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct TruncationWarning {
+    pub original_len: usize,
+    pub truncated_len: usize,
+    pub strategy: TruncationStrategy,
+}
+
+impl fmt::Display for TruncationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Prompt of {} tokens was truncated to {} ({:?}) to fit in the context window.",
+            self.original_len, self.truncated_len, self.strategy
+        )
+    }
+}
+
 #[derive(PartialEq, Copy, Clone)]
 pub enum ModelCategory {
     Text,
@@ -596,12 +627,74 @@ pub trait Pipeline:
     ) -> Result<(), candle_core::Error>;
 
     fn category(&self) -> ModelCategory;
+
+    /// Run a forward pass over `tokens` from an empty KV cache and return the output of each decoder layer in
+    /// `layers`, in the same order, each (1, tokens len, hidden size). The KV cache of the running sequences
+    /// is left as it was. Supporting pipelines cache the results, so repeating a query does not run the model.
+    fn compute_hidden_states(
+        &mut self,
+        _tokens: &[u32],
+        _layers: &[usize],
+    ) -> Result<Vec<Tensor>, candle_core::Error> {
+        candle_core::bail!("Hidden states are not supported for this pipeline.");
+    }
+
+    /// The tokenizer vocabulary size, including added tokens.
+    fn get_vocab_size(&self) -> usize {
+        self.tokenizer().get_vocab_size(true)
+    }
+
+    /// Truncate `tokens` to leave space for `max_new_tokens` (256 by default) in the context window. A warning
+    /// is returned, and logged, if any tokens were dropped.
+    fn truncate_prompt(
+        &self,
+        mut tokens: Vec<u32>,
+        max_new_tokens: Option<usize>,
+        strategy: TruncationStrategy,
+    ) -> (Vec<u32>, Option<TruncationWarning>) {
+        let available = self
+            .get_metadata()
+            .max_seq_len
+            .saturating_sub(max_new_tokens.unwrap_or(256));
+        let n_tokens = tokens.len();
+        if n_tokens <= available {
+            return (tokens, None);
+        }
+        match strategy {
+            TruncationStrategy::Left => {
+                tokens.drain(..n_tokens - available);
+            }
+            TruncationStrategy::Right => tokens.truncate(available),
+            TruncationStrategy::Middle(start_fraction) => {
+                let n_start = (available as f32 * start_fraction.clamp(0., 1.)) as usize;
+                tokens.drain(n_start..n_start + n_tokens - available);
+            }
+        }
+        let warning = TruncationWarning {
+            original_len: n_tokens,
+            truncated_len: available,
+            strategy,
+        };
+        warn!("{warning}");
+        (tokens, Some(warning))
+    }
+
+    /// The number of tokens `text` encodes to, using only the tokenizer.
+    fn count_prompt_tokens(&self, text: &str, add_special_tokens: bool) -> Result<usize> {
+        Ok(self
+            .tokenizer()
+            .encode(text, add_special_tokens)
+            .map_err(anyhow::Error::msg)?
+            .len())
+    }
 }
 
 pub trait NormalModel: IsqModel {
+    /// `padding_mask` is the `attention_mask` of the model inputs, (bs, kv len) with 0 for padding keys.
     fn forward(
         &mut self,
         input_ids: &Tensor,
+        padding_mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
@@ -621,6 +714,18 @@ pub trait NormalModel: IsqModel {
         context_lens: Vec<(usize, usize)>,
         position_ids: Vec<usize>,
     ) -> candle_core::Result<Tensor>;
+    /// The output of each decoder layer in `layers`, in the same order, for a forward pass over `input_ids`
+    /// which uses and updates the cache like `forward`. Each is (bs, seq len, hidden size).
+    fn hidden_states(
+        &mut self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        _layers: &[usize],
+    ) -> candle_core::Result<Vec<Tensor>> {
+        candle_core::bail!("Hidden states are not supported for this model.");
+    }
     fn is_xlora(&self) -> bool;
     fn device(&self) -> &Device;
     fn cache(&self) -> &Cache;
@@ -663,6 +768,22 @@ pub(crate) fn extract_logits(
     Tensor::cat(&toks, 0)
 }
 
+/// The hidden states of `layers`, in order, from the `(layer, hidden state)` pairs collected by a forward pass.
+pub(crate) fn select_hidden_states(
+    hidden_states: Vec<(usize, Tensor)>,
+    layers: &[usize],
+) -> candle_core::Result<Vec<Tensor>> {
+    layers
+        .iter()
+        .map(
+            |layer| match hidden_states.iter().find(|(i, _)| i == layer) {
+                Some((_, xs)) => Ok(xs.clone()),
+                None => candle_core::bail!("The model has no layer {layer}."),
+            },
+        )
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use crate::MessageContent;
@@ -1,4 +1,5 @@
 use super::cache_manager::DefaultCacheManager;
+use super::hidden_states::HiddenStatesCache;
 use super::normal_loaders::{
     GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType, Phi2Loader,
     Phi3Loader, Qwen2Loader,
@@ -50,8 +51,12 @@ pub struct NormalPipeline {
     non_granular_state: Option<NonGranularState>,
     model_id: String,
     metadata: GeneralMetadata,
+    hidden_states_cache: HiddenStatesCache,
 }
 
+/// How many prompts `NormalPipeline::compute_hidden_states` keeps the results of.
+const HIDDEN_STATES_CACHE_PROMPTS: usize = 16;
+
 /// A loader for a "normal" (non-quantized) model.
 pub struct NormalLoader {
     inner: Box<dyn NormalModelLoader>,
@@ -303,6 +308,7 @@ impl Loader for NormalLoader {
                 }
             }),
             model_id: self.model_id.clone(),
+            hidden_states_cache: HiddenStatesCache::new(HIDDEN_STATES_CACHE_PROMPTS),
             metadata: GeneralMetadata {
                 max_seq_len,
                 repeat_last_n: self.config.repeat_last_n,
@@ -340,6 +346,7 @@ impl PreProcessingMixin for NormalPipeline {
 impl IsqPipelineMixin for NormalPipeline {
     fn re_isq_model(&mut self, dtype: GgmlDType) -> Result<()> {
         let device = self.device().clone();
+        self.hidden_states_cache.clear();
         self.model
             .quantize(dtype, device)
             .map_err(anyhow::Error::msg)
@@ -366,6 +373,7 @@ impl CacheManagerMixin for NormalPipeline {
 
 impl AdapterActivationMixin for NormalPipeline {
     fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+        self.hidden_states_cache.clear();
         self.model
             .activate_adapters(adapter_names)
             .map_err(anyhow::Error::msg)
@@ -405,10 +413,15 @@ impl Pipeline for NormalPipeline {
             seqlen_offsets_kernel_full,
             context_lens,
             position_ids,
+            attention_mask,
         } = *inputs.downcast().expect("Downcast failed.");
+        if attention_mask.is_some() && self.model.is_xlora() {
+            candle_core::bail!("Prompt attention masks are not supported for X-LoRA models.");
+        }
         match self.model.is_xlora() {
             false => self.model.forward(
                 &input_ids,
+                attention_mask.as_ref(),
                 &seqlen_offsets,
                 seqlen_offsets_kernel,
                 context_lens,
@@ -441,4 +454,40 @@ impl Pipeline for NormalPipeline {
     fn category(&self) -> ModelCategory {
         ModelCategory::Text
     }
+    fn compute_hidden_states(
+        &mut self,
+        tokens: &[u32],
+        layers: &[usize],
+    ) -> Result<Vec<Tensor>, candle_core::Error> {
+        if let Some(hidden_states) = self.hidden_states_cache.get(tokens, layers) {
+            return Ok(hidden_states);
+        }
+        if tokens.is_empty() {
+            candle_core::bail!("Cannot compute hidden states without tokens.");
+        }
+        let device = self.device();
+        let input_ids = Tensor::new(tokens, &device)?.unsqueeze(0)?;
+        let positions = (0..tokens.len() as i64).collect::<Vec<_>>();
+        let start_offsets_kernel = Tensor::new(positions, &device)?.unsqueeze(0)?;
+
+        // Run from an empty cache, then restore the cache of the running sequences.
+        let saved_cache = {
+            let mut cache = self.model.cache().lock();
+            let n_layers = cache.len();
+            std::mem::replace(&mut *cache, vec![None; n_layers])
+        };
+        let hidden_states = self.model.hidden_states(
+            &input_ids,
+            &[0],
+            start_offsets_kernel,
+            vec![tokens.len()],
+            layers,
+        );
+        *self.model.cache().lock() = saved_cache;
+        let hidden_states = hidden_states?;
+
+        self.hidden_states_cache
+            .insert(tokens, layers, &hidden_states);
+        Ok(hidden_states)
+    }
 }
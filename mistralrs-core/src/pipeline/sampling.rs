@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use candle_core::{DType, Device, Result, Tensor};
+use candle_core::{bail, DType, Device, Result, Tensor};
 use rand_isaac::Isaac64Rng;
 
 use crate::{
@@ -8,6 +8,7 @@ use crate::{
     get_bias_if_not_allowed, sample_async,
     sampler::Logprobs,
     sequence::{Sequence, SequenceRecognizer},
+    utils::distribution::{is_all_masked, log_softmax},
 };
 
 /// Async sample optionally adding to trie.
@@ -24,6 +25,20 @@ pub async fn sample_sequence(
     sample_speculative: bool,
 ) -> Result<Logprobs> {
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    // Teacher forcing: the logprob is that of the forced token under the unmodified distribution.
+    let forced = match seq.next_forced_token() {
+        Some(tok) => {
+            let logprobs = log_softmax(&logits.to_vec1::<f32>()?);
+            let Some(logprob) = logprobs.get(tok as usize) else {
+                bail!(
+                    "Forced token {tok} is out of range for a vocabulary of {} tokens.",
+                    logprobs.len()
+                );
+            };
+            Some((tok, logprob / std::f32::consts::LN_10))
+        }
+        None => None,
+    };
     let start_at = seq.get_toks().len().saturating_sub(repeat_last_n);
 
     let sampler = seq.sampler();
@@ -54,6 +69,12 @@ pub async fn sample_sequence(
             let mut acc = vec![-f32::INFINITY; tok_trie.vocab_size()];
             token_set.apply_to(&mut acc);
             let new_logits = (logits + Tensor::from_slice(&acc, acc.len(), &Device::Cpu)?)?;
+            if is_all_masked(&new_logits.to_vec1::<f32>()?) {
+                bail!(
+                    "All tokens are masked by the grammar at position {}, no token can be sampled.",
+                    seq.get_toks().len()
+                );
+            }
 
             let ctx_clone = seq.get_toks()[start_at..].to_vec();
             let rng_clone = rng.clone();
@@ -70,6 +91,15 @@ pub async fn sample_sequence(
         }
         None => first_lobprobs_response,
     };
+    let second_logprobs_response = match forced {
+        Some((token, logprob)) => Logprobs {
+            token,
+            logprob,
+            bytes: String::from_utf8_lossy(&tok_trie.decode(&[token])).to_string(),
+            top_logprobs: second_logprobs_response.top_logprobs,
+        },
+        None => second_logprobs_response,
+    };
 
     if add_to_trie {
         match seq.recognizer {
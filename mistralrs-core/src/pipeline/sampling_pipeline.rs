@@ -2,7 +2,12 @@
 #[macro_export]
 macro_rules! finish_and_add_tokens_to_seq {
     ($this:expr, $prefix_cacher:expr, $seq:expr, $logprobs:expr, $eos_tok:expr, $use_prefix_cacher:expr) => {{
-        let is_done = $seq.is_done($logprobs.token, $eos_tok, $this.metadata.max_seq_len);
+        let is_done = $seq.is_done(
+            $logprobs.token,
+            $logprobs.logprob,
+            $eos_tok,
+            $this.metadata.max_seq_len,
+        );
         $seq.add_token(
             $logprobs.clone(),
             $this.get_metadata().tok_trie.decode(&[$logprobs.token]),
@@ -97,7 +102,10 @@ macro_rules! finish_and_add_tokens_to_seq {
                     | $crate::sequence::StopReason::ModelLength(_)
                     | $crate::sequence::StopReason::Eos
                     | $crate::sequence::StopReason::StopTok(_)
-                    | $crate::sequence::StopReason::Canceled => {
+                    | $crate::sequence::StopReason::Canceled
+                    | $crate::sequence::StopReason::Timeout
+                    | $crate::sequence::StopReason::ForcedTokensExhausted
+                    | $crate::sequence::StopReason::LowConfidence(_) => {
                         String::from_utf8_lossy($seq.completion_bytes())
                             .trim_start()
                             .to_string()
@@ -149,6 +157,7 @@ macro_rules! finish_and_add_tokens_to_seq {
                                 system_fingerprint: $crate::SYSTEM_FINGERPRINT.to_string(),
                                 object: "chat.completion".to_string(),
                                 usage: group.get_usage(),
+                                user: group.get_user(),
                             },
                             $seq.responder(),
                         )
@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use indexmap::IndexMap;
+
+use crate::sequence::Sequence;
+
+/// Maps prompt hashes (see `Sequence::prompt_hash`) to sequences which can be reused for an identical prompt.
+///
+/// Entries are evicted least recently used first, when either the number of sequences or the total KV cache size
+/// exceeds the configured limit.
+pub struct PromptCache {
+    seqs: IndexMap<u64, Arc<Sequence>>,
+    max_seqs: Option<usize>,
+    max_bytes: Option<usize>,
+    n_bytes: usize,
+}
+
+impl PromptCache {
+    pub fn new(max_seqs: Option<usize>, max_bytes: Option<usize>) -> Self {
+        Self {
+            seqs: IndexMap::new(),
+            max_seqs,
+            max_bytes,
+            n_bytes: 0,
+        }
+    }
+
+    /// Get the sequence cached for this prompt hash, marking it as most recently used.
+    pub fn get(&mut self, prompt_hash: u64) -> Option<Arc<Sequence>> {
+        let seq = self.seqs.shift_remove(&prompt_hash)?;
+        self.seqs.insert(prompt_hash, seq.clone());
+        Some(seq)
+    }
+
+    pub fn contains(&self, prompt_hash: u64) -> bool {
+        self.seqs.contains_key(&prompt_hash)
+    }
+
+    /// Insert a sequence, replacing any with the same prompt hash, then evict until within the limits.
+    pub fn insert(&mut self, seq: Arc<Sequence>) {
+        self.n_bytes += seq.kv_cache_size_bytes();
+        if let Some(old) = self.seqs.shift_remove(&seq.prompt_hash()) {
+            self.n_bytes -= old.kv_cache_size_bytes();
+        }
+        self.seqs.insert(seq.prompt_hash(), seq);
+        self.evict();
+    }
+
+    pub fn remove(&mut self, prompt_hash: u64) -> Option<Arc<Sequence>> {
+        let seq = self.seqs.shift_remove(&prompt_hash)?;
+        self.n_bytes -= seq.kv_cache_size_bytes();
+        Some(seq)
+    }
+
+    fn over_limit(&self) -> bool {
+        self.max_seqs.is_some_and(|max| self.seqs.len() > max)
+            || self.max_bytes.is_some_and(|max| self.n_bytes > max)
+    }
+
+    fn evict(&mut self) {
+        while self.over_limit() {
+            match self.seqs.shift_remove_index(0) {
+                Some((_, seq)) => self.n_bytes -= seq.kv_cache_size_bytes(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seqs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seqs.is_empty()
+    }
+
+    /// Total size of the KV caches of the cached sequences.
+    pub fn size_in_bytes(&self) -> usize {
+        self.n_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{DType, Device, Tensor};
+
+    use super::PromptCache;
+    use crate::sequence::{tests::new_sequence, Sequence};
+
+    /// A sequence with a one layer KV cache of `n_bytes`.
+    fn cached_sequence(tokens: Vec<u32>, n_bytes: usize) -> Arc<Sequence> {
+        let mut seq = new_sequence(tokens);
+        let kv = Tensor::zeros(n_bytes / 8, DType::F32, &Device::Cpu).unwrap();
+        seq.cache()[0] = Some((kv.clone(), kv));
+        Arc::new(seq)
+    }
+
+    #[test]
+    fn hit_and_miss() {
+        let mut cache = PromptCache::new(None, None);
+        let seq = cached_sequence(vec![1, 2], 0);
+        let hash = seq.prompt_hash();
+        assert!(cache.get(hash).is_none());
+
+        cache.insert(seq);
+        assert!(cache.contains(hash));
+        assert_eq!(cache.get(hash).unwrap().prompt_hash(), hash);
+        assert!(cache
+            .get(cached_sequence(vec![2, 1], 0).prompt_hash())
+            .is_none());
+
+        assert!(cache.remove(hash).is_some());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = PromptCache::new(Some(2), None);
+        let seqs = (0..3)
+            .map(|i| cached_sequence(vec![i], 0))
+            .collect::<Vec<_>>();
+        cache.insert(seqs[0].clone());
+        cache.insert(seqs[1].clone());
+        // Using the first makes the second the least recently used.
+        cache.get(seqs[0].prompt_hash());
+        cache.insert(seqs[2].clone());
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(seqs[0].prompt_hash()));
+        assert!(!cache.contains(seqs[1].prompt_hash()));
+        assert!(cache.contains(seqs[2].prompt_hash()));
+    }
+
+    #[test]
+    fn evicts_to_fit_byte_limit() {
+        let mut cache = PromptCache::new(None, Some(100));
+        cache.insert(cached_sequence(vec![1], 64));
+        assert_eq!(cache.size_in_bytes(), 64);
+
+        let seq = cached_sequence(vec![2], 64);
+        let hash = seq.prompt_hash();
+        cache.insert(seq);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.contains(hash));
+        assert_eq!(cache.size_in_bytes(), 64);
+    }
+}
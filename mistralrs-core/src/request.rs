@@ -7,10 +7,11 @@ use std::fmt::Debug;
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
-/// Control the constraint with Regex or Yacc.
+/// Control the constraint with Regex, Yacc or a JSON schema.
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    JsonSchema(serde_json::Value),
     None,
 }
 
@@ -44,6 +45,10 @@ pub struct NormalRequest {
     pub constraint: Constraint,
     pub suffix: Option<String>,
     pub adapters: Option<Vec<String>>,
+    /// Stop the request with an error if it has not finished after this many milliseconds.
+    pub timeout_ms: Option<u128>,
+    /// Returned as the `user` field of the chat completion response, like the OpenAI `user` parameter.
+    pub user: Option<String>,
 }
 
 #[derive(Clone)]
@@ -68,6 +73,8 @@ impl Debug for Request {
                 constraint: _,
                 suffix: _,
                 adapters,
+                timeout_ms: _,
+                user: _,
             }) => {
                 write!(
                     f,
@@ -105,6 +105,10 @@ pub struct Usage {
     pub total_time_sec: f32,
     pub total_prompt_time_sec: f32,
     pub total_completion_time_sec: f32,
+    /// How many prompt tokens were dropped to fit the model's maximum length. Zero if none were.
+    pub input_tokens_truncated: usize,
+    /// Problems with the request which did not stop it, such as prompt truncation.
+    pub warnings: Vec<String>,
 }
 
 generate_repr!(Usage);
@@ -121,6 +125,9 @@ pub struct ChatCompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    /// The user context attached to the request, if any. Mirrors the OpenAI `user` parameter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
 }
 
 generate_repr!(ChatCompletionResponse);
@@ -176,6 +183,7 @@ generate_repr!(CompletionResponse);
 pub enum Response {
     InternalError(Box<dyn Error + Send + Sync>),
     ValidationError(Box<dyn Error + Send + Sync>),
+    TimeoutError(Box<dyn Error + Send + Sync>),
     ModelError(String, ChatCompletionResponse),
     // Chat
     Done(ChatCompletionResponse),
@@ -15,6 +15,10 @@ use rand_isaac::Isaac64Rng;
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
+use crate::utils::distribution::{
+    argsort_descending, is_all_masked, log_softmax, softmax, softmax_last_dim, top_k_indices,
+};
+
 #[derive(Clone, Debug)]
 /// Stop sequences or ids.
 pub enum StopTokens {
@@ -65,6 +69,9 @@ pub struct Sampler {
     logits_bias: Option<Tensor>,
     topk: i64,
     topp: f64,
+    preferred_tokens: Vec<(u32, f32)>,
+    token_mask: Option<Arc<[bool]>>,
+    banned_tokens: Vec<u32>,
 }
 
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
@@ -115,30 +122,109 @@ impl Sampler {
             logits_bias,
             topk,
             topp,
+            preferred_tokens: Vec::new(),
+            token_mask: None,
+            banned_tokens: Vec::new(),
         }
     }
 
-    fn get_top_logprobs(
-        &self,
-        probs: &[f32],
-        argsort_indices: &[usize],
-    ) -> Result<Vec<TopLogprob>> {
-        let mut argsort_indices_sorted = argsort_indices.to_vec();
-        // Sort by descending prob
-        argsort_indices_sorted
-            .sort_by(|a, b| probs[*b].partial_cmp(&probs[*a]).expect("No ordering."));
+    /// Clone this sampler with a mask of the allowed token IDs. Disallowed tokens get a logit of `-inf` before
+    /// anything else is applied, so the returned logprobs are those of the constrained distribution.
+    pub fn with_token_mask(&self, token_mask: Option<Arc<[bool]>>) -> Self {
+        Self {
+            token_mask,
+            ..self.clone()
+        }
+    }
+
+    fn apply_token_mask(&self, logits: &mut [f32]) -> Result<()> {
+        let Some(mask) = &self.token_mask else {
+            return Ok(());
+        };
+        if mask.len() != logits.len() {
+            bail!(
+                "Token mask has length {}, but there are {} logits.",
+                mask.len(),
+                logits.len()
+            );
+        }
+        for (logit, allowed) in logits.iter_mut().zip(mask.iter()) {
+            if !allowed {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        if is_all_masked(logits) {
+            bail!("The token mask does not allow any token.");
+        }
+        Ok(())
+    }
+
+    /// Clone this sampler with tokens which get a logit of `-inf`, applied with the token mask.
+    pub fn with_banned_tokens(&self, banned_tokens: Vec<u32>) -> Self {
+        Self {
+            banned_tokens,
+            ..self.clone()
+        }
+    }
+
+    fn apply_banned_tokens(&self, logits: &mut [f32]) -> Result<()> {
+        if self.banned_tokens.is_empty() {
+            return Ok(());
+        }
+        for token in &self.banned_tokens {
+            if let Some(logit) = logits.get_mut(*token as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        if is_all_masked(logits) {
+            bail!("Every token is banned.");
+        }
+        Ok(())
+    }
+
+    /// Clone this sampler with additive logit biases which are applied after temperature scaling.
+    pub fn with_preferred_tokens(&self, preferred_tokens: Vec<(u32, f32)>) -> Self {
+        Self {
+            preferred_tokens,
+            ..self.clone()
+        }
+    }
+
+    fn apply_preferred_tokens(&self, logits: Tensor) -> Result<Tensor> {
+        if self.preferred_tokens.is_empty() {
+            return Ok(logits);
+        }
+        let mut logits: Vec<f32> = logits.to_vec1()?;
+        for (token, bias) in &self.preferred_tokens {
+            if let Some(logit) = logits.get_mut(*token as usize) {
+                *logit += bias;
+            }
+        }
+        let vocab_size = logits.len();
+        Tensor::from_vec(logits, vocab_size, &Device::Cpu)
+    }
+
+    /// Clone this sampler with a different temperature. The same threshold for argmax sampling as in `new` applies.
+    pub fn with_temperature(&self, temperature: Option<f64>) -> Self {
+        let temperature = if temperature.map_or(true, |v| v < 1e-7) {
+            None
+        } else {
+            temperature
+        };
+        Self {
+            temperature,
+            ..self.clone()
+        }
+    }
+
+    fn get_top_logprobs(&self, probs: &[f32]) -> Result<Vec<TopLogprob>> {
         // These are where the top n are
-        let top_n_toks_range = 0..self.top_n_logprobs;
+        let top_n_toks = top_k_indices(probs, self.top_n_logprobs);
         // The top n's values
-        let top_n_logprobs = argsort_indices_sorted[top_n_toks_range.clone()]
+        let top_n_logprobs = top_n_toks
             .iter()
             .map(|x| probs[*x].log(10.0))
             .collect::<Vec<_>>();
-        // Find where they actually are in the logits
-        let mut top_n_toks = Vec::new();
-        for val in top_n_toks_range {
-            top_n_toks.push(argsort_indices[val]);
-        }
 
         let mut bytes = Vec::new();
         for tok in &top_n_toks {
@@ -160,13 +246,12 @@ impl Sampler {
     fn sample_argmax(&self, logits: Tensor, return_logprobs: bool) -> Result<Logprobs> {
         let next_token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
 
-        let probs: Vec<f32> = logits.to_vec1()?;
-
-        let argsort_indices = (0..probs.len()).collect::<Vec<_>>();
-        let logprob = probs[next_token as usize].log(10.0);
+        // These are raw logits, so normalize them before taking the logprob.
+        let logits: Vec<f32> = logits.to_vec1()?;
+        let logprob = log_softmax(&logits)[next_token as usize] / std::f32::consts::LN_10;
 
         let top_logprobs = if return_logprobs {
-            Some(self.get_top_logprobs(&probs, &argsort_indices)?)
+            Some(self.get_top_logprobs(&softmax(&logits))?)
         } else {
             None
         };
@@ -190,11 +275,7 @@ impl Sampler {
         top_p: f32,
     ) -> Result<Logprobs> {
         let mut probs: Vec<f32> = logits.to_vec1()?;
-        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
-
-        // Sort by descending probability.
-        argsort_indices
-            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+        let argsort_indices = argsort_descending(&probs);
 
         if top_k > 0 {
             // Clamp smaller probabilities to zero.
@@ -228,7 +309,7 @@ impl Sampler {
         let logprob = probs[next_token as usize].log(10.0);
 
         let top_logprobs = if return_logprobs {
-            Some(self.get_top_logprobs(&probs, &argsort_indices)?)
+            Some(self.get_top_logprobs(&probs)?)
         } else {
             None
         };
@@ -247,7 +328,6 @@ impl Sampler {
     fn sample_multinomial(
         &self,
         probs: &mut Vec<f32>,
-        argsort_indices: Vec<usize>,
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
     ) -> Result<Logprobs> {
@@ -258,7 +338,7 @@ impl Sampler {
         let logprob = probs[next_token].log(10.0);
 
         let top_logprobs = if return_logprobs {
-            Some(self.get_top_logprobs(probs, &argsort_indices)?)
+            Some(self.get_top_logprobs(probs)?)
         } else {
             None
         };
@@ -282,11 +362,7 @@ impl Sampler {
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
     ) -> Result<Logprobs> {
-        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
-
-        // Sort by descending probability.
-        argsort_indices
-            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+        let argsort_indices = argsort_descending(probs);
 
         if top_k > 0 {
             // Clamp smaller probabilities to zero.
@@ -298,7 +374,7 @@ impl Sampler {
         }
 
         if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
+            return self.sample_multinomial(probs, return_logprobs, rng);
         }
         // TOP P
 
@@ -317,7 +393,7 @@ impl Sampler {
         }
 
         // Sample with clamped probabilities.
-        self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
+        self.sample_multinomial(probs, return_logprobs, rng)
     }
 
     fn apply_penalties(&self, mut logits: Vec<f32>, context: Option<&[u32]>) -> Result<Tensor> {
@@ -360,22 +436,29 @@ impl Sampler {
         rng: Arc<Mutex<Isaac64Rng>>,
         sample_speculative: bool,
     ) -> Result<Logprobs> {
-        let logits = self.apply_penalties(logits.to_vec1()?, penalty_ctxt)?;
+        let mut logits: Vec<f32> = logits.to_vec1()?;
+        self.apply_token_mask(&mut logits)?;
+        self.apply_banned_tokens(&mut logits)?;
+        let logits = self.apply_penalties(logits, penalty_ctxt)?;
         let logits = match self.logits_bias {
             Some(ref bias) => (logits + bias)?,
             None => logits,
         };
         let next_token = if sample_speculative {
             match self.temperature {
-                None => self.sample_speculative_topkp(
-                    logits,
-                    return_logprobs,
-                    self.topk,
-                    self.topp as f32,
-                )?,
+                None => {
+                    // Normalize the raw logits, so the logprob is a real log-probability.
+                    let probs = softmax_last_dim(&self.apply_preferred_tokens(logits)?)?;
+                    self.sample_speculative_topkp(
+                        probs,
+                        return_logprobs,
+                        self.topk,
+                        self.topp as f32,
+                    )?
+                }
                 Some(temperature) => {
-                    let logits = (&logits / temperature)?;
-                    let probs = candle_nn::ops::softmax_last_dim(&logits)?;
+                    let logits = self.apply_preferred_tokens((&logits / temperature)?)?;
+                    let probs = softmax_last_dim(&logits)?;
 
                     self.sample_speculative_topkp(
                         probs,
@@ -387,10 +470,12 @@ impl Sampler {
             }
         } else {
             match self.temperature {
-                None => self.sample_argmax(logits, return_logprobs)?,
+                None => {
+                    self.sample_argmax(self.apply_preferred_tokens(logits)?, return_logprobs)?
+                }
                 Some(temperature) => {
-                    let logits = (&logits / temperature)?;
-                    let probs = candle_nn::ops::softmax_last_dim(&logits)?;
+                    let logits = self.apply_preferred_tokens((&logits / temperature)?)?;
+                    let probs = softmax_last_dim(&logits)?;
                     let mut probs: Vec<f32> = probs.to_vec1()?;
 
                     self.sample_topkp(
@@ -407,6 +492,19 @@ impl Sampler {
     }
 }
 
+/// The tokens which, appended to `tokens`, would repeat an n-gram of size `n` already in `tokens`.
+pub(crate) fn banned_ngram_tokens(tokens: &[u32], n: usize) -> Vec<u32> {
+    if n == 0 || tokens.len() < n {
+        return Vec::new();
+    }
+    let prefix = &tokens[tokens.len() - (n - 1)..];
+    tokens
+        .windows(n)
+        .filter(|ngram| &ngram[..n - 1] == prefix)
+        .map(|ngram| ngram[n - 1])
+        .collect()
+}
+
 mod tests {
     use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
     use tokenizers::Tokenizer;
@@ -439,7 +537,31 @@ mod tests {
         let res = sampler.sample(logits, None, false, rng, false).unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
-        assert_eq!(res.logprob, 1023f64.log(10.) as f32)
+        // softmax(0..1024)[1023] = 1 - e^-1 up to rounding
+        let expected = (1. - (-1f64).exp()).log(10.) as f32;
+        assert!((res.logprob - expected).abs() < 1e-5)
+    }
+
+    #[test]
+    fn test_argmax_logprob_is_normalized() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let sampler = Sampler::new(None, 10, get_tokenizer().into(), None, None, None, 32, 0.1);
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        // Shifting the logits does not change the distribution, so it must not change the logprob either.
+        for shift in [-100f32, 0., 100.] {
+            let logits = Tensor::new(&[shift, shift + 3f32.ln(), shift], &Device::Cpu).unwrap();
+            let res = sampler
+                .sample(logits, None, false, rng.clone(), false)
+                .unwrap();
+            assert_eq!(res.token, 1);
+            assert!((res.logprob - 0.6f32.log(10.)).abs() < 1e-5);
+        }
     }
 
     #[test]
@@ -457,6 +579,8 @@ mod tests {
         let res = sampler.sample(logits, None, false, rng, true).unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
-        assert_eq!(res.logprob, 1023f64.log(10.) as f32)
+        // The same as `test_argmax`: softmax(0..1024)[1023] = 1 - e^-1 up to rounding
+        let expected = (1. - (-1f64).exp()).log(10.) as f32;
+        assert!((res.logprob - expected).abs() < 1e-5)
     }
 }
@@ -225,9 +225,15 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
 
     /// Schedule all sequences based on their state and the available space.
     pub fn schedule(&mut self) -> SchedulerOutput {
-        // Filter out all done sequences
+        // Filter out all done sequences, including waiting ones finished by a timeout
         let running = std::mem::take(&mut self.running);
-        let mut waiting = std::mem::take(&mut self.waiting);
+        let mut waiting = std::mem::take(&mut self.waiting)
+            .into_iter()
+            .filter(|seq| !seq.is_finished())
+            .fold(Backer::new(), |mut waiting, seq| {
+                waiting.add(seq);
+                waiting
+            });
         let mut running = running
             .into_iter()
             .filter(|seq| seq.is_running())
@@ -1,11 +1,15 @@
 use std::{
+    collections::VecDeque,
     fmt::Display,
-    sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::{
-    mpsc::{error::SendError, Sender},
-    Mutex, MutexGuard,
+use tokio::{
+    sync::{
+        mpsc::{error::SendError, Sender},
+        Mutex, MutexGuard,
+    },
+    task::JoinHandle,
 };
 
 use crate::{
@@ -17,11 +21,13 @@ use crate::{
     get_mut_group,
     pipeline::LayerCaches,
     response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
-    sampler::{Logprobs, Sampler},
+    sampler::{banned_ngram_tokens, Logprobs, Sampler, TopLogprob},
     ChatCompletionResponse, Usage,
 };
-use candle_core::Tensor;
+use candle_core::{DType, Tensor};
 use regex_automata::util::primitives::StateID;
+use tokenizers::Tokenizer;
+use xxhash_rust::xxh64::Xxh64;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum StopReason {
@@ -34,6 +40,9 @@ pub enum StopReason {
         completion_bytes_pos: usize,
     },
     Canceled,
+    ForcedTokensExhausted,
+    LowConfidence(f32),
+    Timeout,
 }
 
 impl Display for StopReason {
@@ -43,6 +52,8 @@ impl Display for StopReason {
             StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
             StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
+            StopReason::Timeout => write!(f, "timeout"),
+            StopReason::ForcedTokensExhausted | StopReason::LowConfidence(_) => write!(f, "stop"),
         }
     }
 }
@@ -63,10 +74,47 @@ pub enum SequenceRecognizer {
     None,
 }
 
+/// The generation state of a [`Sequence`] at one point, for [`Sequence::restore`].
+#[derive(Clone, Debug)]
+pub struct SequenceSnapshot {
+    n_tokens: usize,
+    n_logprobs: usize,
+    n_completion_bytes: usize,
+    last_completion_bytes_len: usize,
+    cumulative_logprob: f32,
+    cache_lens: Vec<Option<usize>>,
+    xlora_cache_lens: Option<Vec<Option<usize>>>,
+}
+
+fn cache_lens(cache: &LayerCaches) -> candle_core::Result<Vec<Option<usize>>> {
+    cache
+        .iter()
+        .map(|layer| layer.as_ref().map(|(k, _)| k.dim(2)).transpose())
+        .collect()
+}
+
+fn trim_cache(cache: &mut LayerCaches, lens: &[Option<usize>]) -> candle_core::Result<()> {
+    for (layer, len) in cache.iter_mut().zip(lens) {
+        let Some(len) = *len else {
+            // The layer was empty at the snapshot.
+            *layer = None;
+            continue;
+        };
+        if let Some((k, v)) = layer {
+            if k.dim(2)? > len {
+                let trimmed = (k.narrow(2, 0, len)?, v.narrow(2, 0, len)?);
+                *layer = Some(trimmed);
+            }
+        }
+    }
+    Ok(())
+}
+
 pub struct Sequence {
     // Metadata, const
     id: usize,
     prompt_len: usize,
+    prompt_hash: u64,
     max_len: Option<usize>,
     timestamp: u128,
     sampler: Arc<Sampler>,
@@ -81,12 +129,24 @@ pub struct Sequence {
     prefix: Option<String>,
     is_tmp: bool,
     adapters: Option<Vec<String>>,
+    temperature_override: Option<f32>,
+    forced_tokens: Option<Vec<u32>>,
+    generation_start_token: Option<u32>,
+    logprob_threshold: Option<f32>,
+    prompt_attention_mask: Option<Vec<bool>>, // `false` for padding positions in the prompt.
+    on_error: Option<Arc<dyn Fn(&Sequence) + Send + Sync>>,
+    preferred_tokens: Vec<(u32, f32)>, // Token and additive logit bias.
+    token_mask: Option<Arc<[bool]>>,
+    no_repeat_ngram_size: Option<usize>,
+    temperature_schedule: Option<TemperatureSchedule>,
 
     // Cache
     scaling_cache: Option<Tensor>,
     cache: LayerCaches,
     draft_cache: LayerCaches,
     xlora_cache: Option<LayerCaches>,
+    block_size: usize,
+    block_tables: Vec<Vec<bool>>, // Per layer, whether each block ID is allocated.
 
     // Mutables
     tokens: Vec<u32>,
@@ -97,6 +157,7 @@ pub struct Sequence {
     last_is_done: Option<StopReason>,
     completion_bytes: Vec<u8>,
     stream_idx: usize,
+    last_streamed_idx: usize, // Index into `tokens` of the first token not yet returned by `get_delta_toks`.
     pub recognizer: SequenceRecognizer,
     scheduling_urgency: usize, // The number of passes since scheduling
     input_images: Option<Vec<image::DynamicImage>>,
@@ -105,7 +166,25 @@ pub struct Sequence {
     pub prompt_tok_per_sec: f32,
     pub prompt_timestamp: Option<u128>,
     group: Arc<Mutex<SequenceGroup>>,
-    state: RwLock<SequenceState>,
+    group_signals: Arc<RwLock<GroupSignals>>, // Shared with the group, see `join_group_signals`.
+    state: Arc<RwLock<SequenceState>>, // Shared with the group, so a timeout can finish the sequence.
+}
+
+const DEFAULT_BLOCK_SIZE: usize = 16;
+
+/// The sampling temperature for a step, given the number of tokens generated so far.
+pub type TemperatureSchedule = Arc<dyn Fn(usize) -> f32 + Send + Sync>;
+
+/// Anneal the temperature linearly from `start` to `end` over the first `n_steps` generated tokens,
+/// then keep it at `end`.
+pub fn linear_annealing_schedule(start: f32, end: f32, n_steps: usize) -> TemperatureSchedule {
+    Arc::new(move |step| {
+        if n_steps == 0 {
+            return end;
+        }
+        let t = step.min(n_steps) as f32 / n_steps as f32;
+        start + (end - start) * t
+    })
 }
 
 impl Sequence {
@@ -132,13 +211,15 @@ impl Sequence {
         input_images: Option<Vec<image::DynamicImage>>,
     ) -> Self {
         let prompt_len = tokens.len();
+        let prompt_hash = Self::hash_tokens(&tokens);
         Self {
             tokens,
             logprobs: Vec::new(),
             prompt_len,
+            prompt_hash,
             id,
             timestamp,
-            state: RwLock::new(SequenceState::Waiting),
+            state: Arc::new(RwLock::new(SequenceState::Waiting)),
             cache: vec![None; layers],
             draft_cache: vec![None; layers],
             xlora_cache: if is_xlora {
@@ -146,6 +227,8 @@ impl Sequence {
             } else {
                 None
             },
+            block_size: DEFAULT_BLOCK_SIZE,
+            block_tables: vec![Vec::new(); layers],
             responder,
             sampler: sampler.into(),
             stop_tokens,
@@ -155,6 +238,7 @@ impl Sequence {
             prompt_tok_per_sec: 0.,
             prompt_timestamp: None,
             group,
+            group_signals: Arc::default(),
             scaling_cache: None,
             response_index,
             creation_time,
@@ -165,6 +249,7 @@ impl Sequence {
             cumulative_logprob: 0.,
             completion_bytes: Vec::new(),
             stream_idx: 0,
+            last_streamed_idx: prompt_len,
             last_completion_bytes_len: 0,
             last_logprob: 0.0,
             last_is_done: None,
@@ -172,9 +257,83 @@ impl Sequence {
             scheduling_urgency: 0,
             adapters,
             input_images,
+            temperature_override: None,
+            forced_tokens: None,
+            generation_start_token: None,
+            logprob_threshold: None,
+            prompt_attention_mask: None,
+            on_error: None,
+            preferred_tokens: Vec::new(),
+            token_mask: None,
+            no_repeat_ngram_size: None,
+            temperature_schedule: None,
         }
     }
 
+    /// Register this sequence with the signals of its group, from [`SequenceGroup::signals`]. This lets the
+    /// group's timeout finish the sequence. It is separate from `new_waiting` so that neither takes the group
+    /// lock, which may already be held by the caller.
+    pub fn join_group_signals(&mut self, signals: Arc<RwLock<GroupSignals>>) {
+        signals
+            .write()
+            .unwrap()
+            .seq_states
+            .push(Arc::downgrade(&self.state));
+        self.group_signals = signals;
+    }
+
+    /// Create a waiting sequence whose prompt is `system_tokens`, then `separator` if given, then `user_tokens`.
+    /// The prompt length includes all of them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_system_prompt(
+        system_tokens: Vec<u32>,
+        user_tokens: Vec<u32>,
+        separator: Option<u32>,
+        id: usize,
+        timestamp: u128,
+        layers: usize,
+        responder: Sender<Response>,
+        sampler: Sampler,
+        stop_tokens: Vec<u32>,
+        stop_strings: Vec<String>,
+        max_len: Option<usize>,
+        return_logprobs: bool,
+        is_xlora: bool,
+        group: Arc<Mutex<SequenceGroup>>,
+        response_index: usize,
+        creation_time: u64,
+        recognizer: SequenceRecognizer,
+        suffix: Option<String>,
+        prefix: Option<String>,
+        adapters: Option<Vec<String>>,
+        input_images: Option<Vec<image::DynamicImage>>,
+    ) -> Self {
+        let mut tokens = system_tokens;
+        tokens.extend(separator);
+        tokens.extend(user_tokens);
+        Self::new_waiting(
+            tokens,
+            id,
+            timestamp,
+            layers,
+            responder,
+            sampler,
+            stop_tokens,
+            stop_strings,
+            max_len,
+            return_logprobs,
+            is_xlora,
+            group,
+            response_index,
+            creation_time,
+            recognizer,
+            suffix,
+            prefix,
+            adapters,
+            input_images,
+        )
+    }
+
     pub fn add_urgency(mut self) -> Self {
         self.scheduling_urgency += 1;
         self
@@ -252,6 +411,31 @@ impl Sequence {
         *self.state.read().unwrap() == SequenceState::Waiting
     }
 
+    pub fn is_error(&self) -> bool {
+        *self.state.read().unwrap() == SequenceState::Error
+    }
+
+    /// Whether the sequence is in the `Done` state. Not to be confused with `is_done`, which checks
+    /// whether a new token should stop it.
+    pub fn is_finished(&self) -> bool {
+        matches!(*self.state.read().unwrap(), SequenceState::Done(_))
+    }
+
+    /// Register a hook called whenever the sequence is set to the `Error` state.
+    pub fn set_on_error(&mut self, on_error: Option<Arc<dyn Fn(&Sequence) + Send + Sync>>) {
+        self.on_error = on_error;
+    }
+
+    /// The prompt tokens, excluding any generated tokens.
+    pub fn prompt_tokens_slice(&self) -> &[u32] {
+        &self.tokens[..self.prompt_len]
+    }
+
+    /// The generated tokens, excluding the prompt.
+    pub fn generated_tokens_slice(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+
     pub fn get_toks(&self) -> &[u32] {
         if let Some(toks) = &self.prefill_prompt_toks {
             return toks;
@@ -259,10 +443,26 @@ impl Sequence {
         &self.tokens
     }
 
-    /// This will also set prompt_len
+    /// This will also set prompt_len and prompt_hash
     pub(crate) fn set_toks(&mut self, toks: Vec<u32>) {
         self.tokens = toks;
         self.prompt_len = self.tokens.len();
+        self.prompt_hash = Self::hash_tokens(&self.tokens);
+    }
+
+    /// XXH64, with a seed of 0, of the tokens' little-endian bytes. Unlike `DefaultHasher`, this is the same in
+    /// every process and on every platform, so prompt hashes can be stored and compared between runs.
+    fn hash_tokens(toks: &[u32]) -> u64 {
+        let mut hasher = Xxh64::new(0);
+        for tok in toks {
+            hasher.update(&tok.to_le_bytes());
+        }
+        hasher.digest()
+    }
+
+    /// Hash of the prompt tokens, computed at construction. Used as the `PromptCache` key.
+    pub fn prompt_hash(&self) -> u64 {
+        self.prompt_hash
     }
 
     pub fn completion_bytes(&self) -> &[u8] {
@@ -273,6 +473,107 @@ impl Sequence {
         &mut self.cache
     }
 
+    /// Total size of the tensors in the KV cache, including the X-LoRA cache if present.
+    pub fn kv_cache_size_bytes(&self) -> usize {
+        self.cache
+            .iter()
+            .chain(self.xlora_cache.iter().flatten())
+            .flatten()
+            .map(|(k, v)| (k.elem_count() + v.elem_count()) * k.dtype().size_in_bytes())
+            .sum()
+    }
+
+    /// Per layer shape and dtype of the cached K tensor (V has the same shape), for debugging the KV cache.
+    pub fn cache_debug(&self) -> Vec<Option<(Vec<usize>, DType)>> {
+        self.cache
+            .iter()
+            .map(|layer| layer.as_ref().map(|(k, _)| (k.dims().to_vec(), k.dtype())))
+            .collect()
+    }
+
+    /// Number of tokens per KV cache block.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn set_block_size(&mut self, block_size: usize) -> candle_core::Result<()> {
+        if block_size == 0 {
+            candle_core::bail!("Block size must be nonzero.");
+        }
+        self.block_size = block_size;
+        Ok(())
+    }
+
+    /// Split the KV cache along the sequence dimension into blocks of `block_size` tokens.
+    /// Each block has an entry per layer; the last block may be shorter.
+    pub fn kv_cache_as_blocks(&self) -> candle_core::Result<Vec<LayerCaches>> {
+        // The cache is (batch, heads, seq, head_dim).
+        let seq_len = self
+            .cache
+            .iter()
+            .flatten()
+            .map(|(k, _)| k.dim(2))
+            .collect::<candle_core::Result<Vec<_>>>()?
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+        let n_blocks = seq_len.div_ceil(self.block_size);
+        let mut blocks = Vec::with_capacity(n_blocks);
+        for block in 0..n_blocks {
+            let start = block * self.block_size;
+            let layers = self
+                .cache
+                .iter()
+                .map(|layer| {
+                    let Some((k, v)) = layer else {
+                        return Ok(None);
+                    };
+                    let layer_len = k.dim(2)?;
+                    if layer_len <= start {
+                        return Ok(None);
+                    }
+                    let len = self.block_size.min(layer_len - start);
+                    Ok(Some((k.narrow(2, start, len)?, v.narrow(2, start, len)?)))
+                })
+                .collect::<candle_core::Result<LayerCaches>>()?;
+            blocks.push(layers);
+        }
+        Ok(blocks)
+    }
+
+    /// Allocate a KV cache block for `layer`, reusing the lowest freed block ID if there is one.
+    pub fn allocate_block(&mut self, layer: usize) -> candle_core::Result<usize> {
+        let table = self.block_table_mut(layer)?;
+        Ok(match table.iter().position(|allocated| !allocated) {
+            Some(block_id) => {
+                table[block_id] = true;
+                block_id
+            }
+            None => {
+                table.push(true);
+                table.len() - 1
+            }
+        })
+    }
+
+    /// Free a block allocated by `allocate_block`. Freeing a block which is not allocated does nothing.
+    pub fn free_block(&mut self, layer: usize, block_id: usize) -> candle_core::Result<()> {
+        if let Some(allocated) = self.block_table_mut(layer)?.get_mut(block_id) {
+            *allocated = false;
+        }
+        Ok(())
+    }
+
+    fn block_table_mut(&mut self, layer: usize) -> candle_core::Result<&mut Vec<bool>> {
+        let n_layers = self.block_tables.len();
+        match self.block_tables.get_mut(layer) {
+            Some(table) => Ok(table),
+            None => {
+                candle_core::bail!("Layer {layer} is out of range, there are {n_layers} layers.")
+            }
+        }
+    }
+
     pub fn draft_cache(&mut self) -> &mut Vec<Option<(Tensor, Tensor)>> {
         &mut self.draft_cache
     }
@@ -289,8 +590,82 @@ impl Sequence {
         self.xlora_cache.is_some()
     }
 
+    /// If a temperature override, preferred tokens or a token mask are set, this is a copy of the sampler using them.
     pub fn sampler(&mut self) -> Arc<Sampler> {
-        self.sampler.clone()
+        if self.temperature_override.is_none()
+            && self.temperature_schedule.is_none()
+            && self.preferred_tokens.is_empty()
+            && self.token_mask.is_none()
+            && self.no_repeat_ngram_size.is_none()
+        {
+            return self.sampler.clone();
+        }
+        let mut sampler = (*self.sampler).clone();
+        let temperature = match &self.temperature_schedule {
+            Some(schedule) => Some(schedule(self.n_tokens_generated())),
+            None => self.temperature_override,
+        };
+        if let Some(temperature) = temperature {
+            sampler = sampler.with_temperature(Some(temperature.into()));
+        }
+        if !self.preferred_tokens.is_empty() {
+            sampler = sampler.with_preferred_tokens(self.preferred_tokens.clone());
+        }
+        if self.token_mask.is_some() {
+            sampler = sampler.with_token_mask(self.token_mask.clone());
+        }
+        if let Some(n) = self.no_repeat_ngram_size {
+            sampler = sampler.with_banned_tokens(banned_ngram_tokens(&self.tokens, n));
+        }
+        Arc::new(sampler)
+    }
+
+    /// Set the temperature before each sampling step from `schedule`, taking precedence over the
+    /// temperature override.
+    pub fn set_temperature_schedule(&mut self, schedule: Option<TemperatureSchedule>) {
+        self.temperature_schedule = schedule;
+    }
+
+    pub fn temperature_override(&self) -> Option<f32> {
+        self.temperature_override
+    }
+
+    /// Override the sampler temperature for this sequence only. `None` restores the sampler's own temperature.
+    pub fn set_temperature_override(&mut self, temperature: Option<f32>) {
+        self.temperature_override = temperature;
+    }
+
+    pub fn preferred_tokens(&self) -> &[(u32, f32)] {
+        &self.preferred_tokens
+    }
+
+    /// Softly bias sampling toward `token` by adding `bias` to its logit, after temperature scaling.
+    pub fn add_preferred_token(&mut self, token: u32, bias: f32) {
+        self.preferred_tokens.push((token, bias));
+    }
+
+    pub fn clear_preferred_tokens(&mut self) {
+        self.preferred_tokens.clear();
+    }
+
+    /// Only allow sampling the tokens whose entry in `mask` is true, until the mask is changed or cleared.
+    /// The mask must have an entry for every token in the vocabulary.
+    pub fn set_token_mask(&mut self, mask: Vec<bool>) {
+        self.token_mask = Some(mask.into());
+    }
+
+    pub fn clear_token_mask(&mut self) {
+        self.token_mask = None;
+    }
+
+    pub fn no_repeat_ngram_size(&self) -> Option<usize> {
+        self.no_repeat_ngram_size
+    }
+
+    /// Never generate a token which would repeat an n-gram of this size, counting the prompt, as with
+    /// HF Transformers' `no_repeat_ngram_size`. This stops greedy decoding from looping.
+    pub fn set_no_repeat_ngram_size(&mut self, n: Option<usize>) {
+        self.no_repeat_ngram_size = n;
     }
 
     /// Add a some prefill tokens. Only meant for internal speculative decoding usage.
@@ -315,6 +690,232 @@ impl Sequence {
         self.tokens.truncate(self.tokens.len() - n);
     }
 
+    /// Copy the per-sequence settings which `new_waiting` does not take, such as the sampling overrides,
+    /// teacher forcing and stop threshold, to `other`.
+    fn copy_per_sequence_params_to(&self, other: &mut Sequence) {
+        let Self {
+            temperature_override,
+            temperature_schedule,
+            forced_tokens,
+            generation_start_token,
+            logprob_threshold,
+            prompt_attention_mask,
+            on_error,
+            preferred_tokens,
+            token_mask,
+            no_repeat_ngram_size,
+            ..
+        } = self;
+        other.temperature_override = *temperature_override;
+        other.temperature_schedule = temperature_schedule.clone();
+        other.forced_tokens = forced_tokens.clone();
+        other.generation_start_token = *generation_start_token;
+        other.logprob_threshold = *logprob_threshold;
+        other.prompt_attention_mask = prompt_attention_mask.clone();
+        other.on_error = on_error.clone();
+        other.preferred_tokens = preferred_tokens.clone();
+        other.token_mask = token_mask.clone();
+        other.no_repeat_ngram_size = *no_repeat_ngram_size;
+    }
+
+    /// Split the prompt at `position` for prefix sharing. The prefix is a new waiting sequence of the
+    /// tokens before `position`, with its own group, `new_id` and `responder`, and no constraint. The suffix
+    /// continues this sequence: it has every token but, if this sequence's KV cache covers the prefix, it
+    /// starts from that cache (trimmed to `position`) and only processes the tokens from `position` on.
+    pub fn split_at_token(
+        &self,
+        position: usize,
+        new_id: usize,
+        responder: Sender<Response>,
+    ) -> candle_core::Result<(Sequence, Sequence)> {
+        if position > self.prompt_len {
+            candle_core::bail!(
+                "Cannot split at token {position}, the prompt only has {} tokens.",
+                self.prompt_len
+            );
+        }
+        let recognizer = match &self.recognizer {
+            SequenceRecognizer::Regex(rx) => SequenceRecognizer::Regex(rx.clone()),
+            SequenceRecognizer::None => SequenceRecognizer::None,
+            SequenceRecognizer::Cfg(_) => {
+                candle_core::bail!("Cannot split a sequence constrained by a grammar.")
+            }
+        };
+        let layers = self.cache.len();
+        let is_xlora = self.xlora_cache.is_some();
+        let is_chat = get_mut_group!(self).is_chat;
+
+        let prefix_group = SequenceGroup::new(1, false, is_chat, 1);
+        let prefix_signals = prefix_group.signals();
+        let mut prefix = Sequence::new_waiting(
+            self.tokens[..position].to_vec(),
+            new_id,
+            self.timestamp,
+            layers,
+            responder,
+            (*self.sampler).clone(),
+            self.stop_tokens.clone(),
+            self.stop_strings.clone(),
+            self.max_len,
+            self.return_logprobs,
+            is_xlora,
+            Arc::new(Mutex::new(prefix_group)),
+            0,
+            self.creation_time,
+            SequenceRecognizer::None,
+            None,
+            None,
+            self.adapters.clone(),
+            self.input_images.clone(),
+        );
+
+        let mut suffix = Sequence::new_waiting(
+            self.tokens.clone(),
+            self.id,
+            self.timestamp,
+            layers,
+            self.responder.clone(),
+            (*self.sampler).clone(),
+            self.stop_tokens.clone(),
+            self.stop_strings.clone(),
+            self.max_len,
+            self.return_logprobs,
+            is_xlora,
+            self.group.clone(),
+            self.response_index,
+            self.creation_time,
+            recognizer,
+            self.suffix.clone(),
+            self.prefix.clone(),
+            self.adapters.clone(),
+            self.input_images.clone(),
+        );
+        prefix.join_group_signals(prefix_signals);
+        self.copy_per_sequence_params_to(&mut suffix);
+        suffix.block_size = self.block_size;
+        suffix.join_group_signals(self.group_signals.clone());
+
+        let covers_prefix = |cache: &LayerCaches| -> candle_core::Result<bool> {
+            Ok(cache_lens(cache)?
+                .iter()
+                .all(|len| len.is_some_and(|len| len >= position)))
+        };
+        let suffix = if position > 0 && covers_prefix(&self.cache)? {
+            let lens = vec![Some(position); layers];
+            let mut cache = self.cache.clone();
+            trim_cache(&mut cache, &lens)?;
+            let xlora_cache = match &self.xlora_cache {
+                Some(xlora_cache) if covers_prefix(xlora_cache)? => {
+                    let mut xlora_cache = xlora_cache.clone();
+                    trim_cache(&mut xlora_cache, &lens)?;
+                    Some(xlora_cache)
+                }
+                _ => None,
+            };
+            let toks = self.tokens[position..].to_vec();
+            suffix.prefill(cache, xlora_cache, toks)
+        } else {
+            suffix
+        };
+        Ok((prefix, suffix))
+    }
+
+    /// The number of prompt tokens whose KV cache was provided by `prefill`, and so are not processed again.
+    pub fn prefix_cache_len(&self) -> usize {
+        match &self.prefill_prompt_toks {
+            Some(toks) => self.tokens.len() - toks.len(),
+            None => 0,
+        }
+    }
+
+    /// Capture the current generation state so it can be restored later. The KV cache is not copied,
+    /// only its length is recorded.
+    pub fn snapshot(&self) -> candle_core::Result<SequenceSnapshot> {
+        Ok(SequenceSnapshot {
+            n_tokens: self.tokens.len(),
+            n_logprobs: self.logprobs.len(),
+            n_completion_bytes: self.completion_bytes.len(),
+            last_completion_bytes_len: self.last_completion_bytes_len,
+            cumulative_logprob: self.cumulative_logprob,
+            cache_lens: cache_lens(&self.cache)?,
+            xlora_cache_lens: self.xlora_cache.as_ref().map(cache_lens).transpose()?,
+        })
+    }
+
+    /// Undo everything generated since `snap` was taken. The tokens, logprobs and completion bytes are
+    /// truncated, and each KV cache tensor is trimmed along the sequence dimension to its length at the
+    /// snapshot, so the next forward pass continues from there. A snapshot can only move a sequence back.
+    pub fn restore(&mut self, snap: &SequenceSnapshot) -> candle_core::Result<()> {
+        self.tokens.truncate(snap.n_tokens);
+        self.logprobs.truncate(snap.n_logprobs);
+        self.completion_bytes.truncate(snap.n_completion_bytes);
+        self.stream_idx = self.stream_idx.min(snap.n_completion_bytes);
+        self.last_streamed_idx = self.last_streamed_idx.min(snap.n_tokens);
+        self.last_completion_bytes_len = snap.last_completion_bytes_len;
+        self.cumulative_logprob = snap.cumulative_logprob;
+        self.last_is_done = None;
+        trim_cache(&mut self.cache, &snap.cache_lens)?;
+        if let (Some(cache), Some(lens)) = (&mut self.xlora_cache, &snap.xlora_cache_lens) {
+            trim_cache(cache, lens)?;
+        }
+        Ok(())
+    }
+
+    /// Force the generated tokens, in order, instead of sampling them (teacher forcing).
+    /// The sequence stops with `StopReason::ForcedTokensExhausted` after the last forced token.
+    pub fn set_forced_tokens(&mut self, toks: Option<Vec<u32>>) {
+        self.forced_tokens = toks;
+    }
+
+    pub fn forced_tokens(&self) -> Option<&[u32]> {
+        self.forced_tokens.as_deref()
+    }
+
+    /// Stop with `StopReason::LowConfidence` once a token's logprob (log10, as in `Logprobs`) is below `threshold`.
+    pub fn set_logprob_threshold(&mut self, threshold: Option<f32>) {
+        self.logprob_threshold = threshold;
+    }
+
+    pub fn logprob_threshold(&self) -> Option<f32> {
+        self.logprob_threshold
+    }
+
+    /// Mark prompt positions which must not be attended to with `false`, such as padding. Positions past the
+    /// end of the mask, including all generated tokens, are attended to.
+    pub fn set_prompt_attention_mask(&mut self, mask: Option<Vec<bool>>) {
+        self.prompt_attention_mask = mask;
+    }
+
+    pub fn prompt_attention_mask(&self) -> Option<&[bool]> {
+        self.prompt_attention_mask.as_deref()
+    }
+
+    /// Force the first generated token, for example a language token. It counts toward the generated length.
+    pub fn set_generation_start_token(&mut self, tok: Option<u32>) {
+        self.generation_start_token = tok;
+    }
+
+    pub fn generation_start_token(&self) -> Option<u32> {
+        self.generation_start_token
+    }
+
+    /// The forced token for the next generated position, if there is one. The generation start token
+    /// takes precedence over `forced_tokens` for the first position.
+    pub fn next_forced_token(&self) -> Option<u32> {
+        match self.n_generated_since_forcing() {
+            Some(n) => self.forced_tokens.as_ref()?.get(n).copied(),
+            None => self.generation_start_token,
+        }
+    }
+
+    /// The number of tokens generated since `forced_tokens` began to apply, which is after the generation
+    /// start token if there is one. `None` if the next token is the generation start token.
+    fn n_generated_since_forcing(&self) -> Option<usize> {
+        self.generated_tokens_slice()
+            .len()
+            .checked_sub(usize::from(self.generation_start_token.is_some()))
+    }
+
     pub fn add_token(
         &mut self,
         tok: Logprobs,
@@ -350,15 +951,33 @@ impl Sequence {
     }
 
     pub fn set_state(&self, state: SequenceState) {
-        if matches!(state, SequenceState::Error) {
-            get_mut_group!(self).n_choices -= 1;
+        match state {
+            SequenceState::Error => {
+                let mut group = get_mut_group!(self);
+                group.n_choices = group.n_choices.saturating_sub(1);
+            }
+            SequenceState::Done(_) => {
+                let mut group = get_mut_group!(self);
+                if group.early_stop_on_first_done
+                    && self.group_signals.read().unwrap().stop_signal.is_none()
+                {
+                    group.broadcast_stop_signal(StopReason::Canceled);
+                }
+            }
+            _ => {}
         }
         *self.state.write().unwrap() = state;
+        if state == SequenceState::Error {
+            if let Some(on_error) = &self.on_error {
+                on_error(self);
+            }
+        }
     }
 
     pub fn is_done(
         &self,
         tok: u32,
+        logprob: f32,
         eos_tok: Option<&[u32]>,
         max_model_len: usize,
     ) -> Option<StopReason> {
@@ -366,15 +985,28 @@ impl Sequence {
             Some(eos_tok) => eos_tok.iter().any(|t| *t == tok),
             None => false,
         };
+        let stop_signal = self.group_signals.read().unwrap().stop_signal;
         if is_eos {
             Some(StopReason::Eos)
-        } else if matches!(
-            &*self.state.read().unwrap(),
-            SequenceState::Done(StopReason::Canceled)
-        ) {
-            Some(StopReason::Canceled)
+        } else if let SequenceState::Done(reason @ (StopReason::Canceled | StopReason::Timeout)) =
+            *self.state.read().unwrap()
+        {
+            Some(reason)
+        } else if let Some(reason) = stop_signal {
+            Some(reason)
+        } else if self
+            .logprob_threshold
+            .is_some_and(|threshold| logprob < threshold)
+        {
+            Some(StopReason::LowConfidence(logprob))
         } else if self.stop_tokens.contains(&tok) {
             Some(StopReason::StopTok(tok))
+        } else if self.forced_tokens.as_ref().is_some_and(|forced| {
+            // add_token has not been called yet, so this is the last forced token
+            self.n_generated_since_forcing()
+                .is_some_and(|n| n + 1 >= forced.len())
+        }) {
+            Some(StopReason::ForcedTokensExhausted)
         } else if self.max_len.is_some()
             && self.tokens.len().saturating_sub(self.prompt_len) == self.max_len.unwrap()
         {
@@ -402,6 +1034,38 @@ impl Sequence {
         &self.logprobs
     }
 
+    pub fn n_tokens_generated(&self) -> usize {
+        self.generated_tokens_slice().len()
+    }
+
+    /// The logprob of each generated token, like HF Transformers' `compute_transition_scores`.
+    /// Empty unless logprobs are returned.
+    pub fn compute_transition_scores(&self) -> Vec<f32> {
+        if !self.return_logprobs {
+            return Vec::new();
+        }
+        self.logprobs.iter().map(|lp| lp.logprob).collect()
+    }
+
+    /// `compute_transition_scores`, each divided by the number of generated tokens.
+    pub fn compute_transition_scores_normalized(&self) -> Vec<f32> {
+        let n = self.n_tokens_generated().max(1) as f32;
+        self.compute_transition_scores()
+            .into_iter()
+            .map(|score| score / n)
+            .collect()
+    }
+
+    /// The logprobs of the most recently added token.
+    pub fn last_logprob(&self) -> Option<&Logprobs> {
+        self.logprobs.last()
+    }
+
+    /// The alternatives considered for the most recently added token, if top logprobs were requested.
+    pub fn top_logprobs(&self) -> Option<&[TopLogprob]> {
+        self.logprobs.last()?.top_logprobs.as_deref()
+    }
+
     pub fn return_logprobs(&self) -> bool {
         self.return_logprobs
     }
@@ -414,6 +1078,18 @@ impl Sequence {
         &self.stop_strings
     }
 
+    /// The generated tokens since the last call, so a stream need not re-process old tokens.
+    pub fn get_delta_toks(&mut self) -> &[u32] {
+        let start = self.last_streamed_idx.min(self.tokens.len());
+        self.last_streamed_idx = self.tokens.len();
+        &self.tokens[start..]
+    }
+
+    /// Restart `get_delta_toks` from the first generated token.
+    pub fn reset_delta_pointer(&mut self) {
+        self.last_streamed_idx = self.prompt_len;
+    }
+
     /// Returns the delta between the last two decoded sequences
     pub fn get_delta(
         &mut self,
@@ -462,6 +1138,7 @@ impl Sequence {
 
     pub fn add_choice_to_group(&self, choice: Choice) {
         get_mut_group!(self).choices.push(choice);
+        self.add_choice_tokens_to_group();
         self.update_time_info();
     }
 
@@ -475,9 +1152,17 @@ impl Sequence {
         get_mut_group!(self)
             .completion_choices
             .push((self.cumulative_logprob, choice));
+        self.add_choice_tokens_to_group();
         self.update_time_info();
     }
 
+    fn add_choice_tokens_to_group(&self) {
+        get_mut_group!(self).choice_tokens.push((
+            self.cumulative_logprob,
+            self.generated_tokens_slice().to_vec(),
+        ));
+    }
+
     pub fn get_response_index(&self) -> usize {
         self.response_index
     }
@@ -487,7 +1172,7 @@ impl Sequence {
     }
 
     pub fn add_streaming_chunk_choice_to_group(&self, chunk: ChunkChoice) {
-        get_mut_group!(self).streaming_chunks.push(chunk);
+        get_mut_group!(self).push_streaming_chunk(chunk);
     }
 
     pub fn get_adapters(&self) -> Option<Vec<String>> {
@@ -503,6 +1188,21 @@ impl Sequence {
     }
 }
 
+/// The model dimensions used by [`SequenceGroup::estimated_memory_bytes`] to estimate activation memory.
+#[derive(Clone, Copy, Debug)]
+pub struct ModelConfig {
+    pub hidden_size: usize,
+    pub n_heads: usize,
+}
+
+/// The parts of a [`SequenceGroup`] which its sequences and its timeout task use without taking the group
+/// lock, as it is a spin lock for the sequences.
+#[derive(Default)]
+pub struct GroupSignals {
+    seq_states: Vec<Weak<RwLock<SequenceState>>>, // The states of the sequences joined to the group.
+    stop_signal: Option<StopReason>,              // Set by `SequenceGroup::broadcast_stop_signal`.
+}
+
 pub struct SequenceGroup {
     n_choices: usize, // The target number of choices to return. Can be decreased if an error is thrown.
     best_of: usize,   // Top n seqs based on cumulative logprobs.
@@ -511,11 +1211,26 @@ pub struct SequenceGroup {
     pub total_prompt_time: u128,
     pub total_time: u128,
     pub total_completion_time: u128,
+    pub input_tokens_truncated: usize,
     choices: Vec<Choice>,
     completion_choices: Vec<(f32, CompletionChoice)>,
-    pub streaming_chunks: Vec<ChunkChoice>,
+    choice_tokens: Vec<(f32, Vec<u32>)>, // Cumulative logprob and generated tokens, in choice order.
+    pub streaming_chunks: VecDeque<ChunkChoice>,
+    sent_streaming_text: Vec<String>, // Per choice, the deltas of its streaming chunks already sent, in order.
+    sent_chunk_ends: Vec<Vec<usize>>, // Per choice, the end of each sent chunk in its `sent_streaming_text`.
     pub is_streaming: bool,
     pub is_chat: bool,
+    last_chunk_timestamp: Option<u128>,
+    max_chunks_buffered: Option<usize>,
+    dropped_chunks: usize,
+    max_chunks_before_flush: Option<usize>,
+    flush_interval_ms: Option<u128>,
+    timeout_ms: Option<u128>,
+    timeout_task: Option<JoinHandle<()>>,
+    signals: Arc<RwLock<GroupSignals>>,
+    pub early_stop_on_first_done: bool, // Stop the other choices once one finishes.
+    user_context: Option<serde_json::Value>,
+    streaming_paused: bool,
 }
 
 impl SequenceGroup {
@@ -523,17 +1238,103 @@ impl SequenceGroup {
         Self {
             choices: Vec::new(),
             completion_choices: Vec::new(),
+            choice_tokens: Vec::new(),
             n_choices,
             total_prompt_toks: 0,
             total_toks: 0,
             total_prompt_time: 0,
             total_time: 0,
             total_completion_time: 0,
-            streaming_chunks: Vec::new(),
+            input_tokens_truncated: 0,
+            user_context: None,
+            streaming_paused: false,
+            streaming_chunks: VecDeque::new(),
+            sent_streaming_text: Vec::new(),
+            sent_chunk_ends: Vec::new(),
             is_streaming,
             is_chat,
             best_of,
+            last_chunk_timestamp: None,
+            max_chunks_buffered: None,
+            dropped_chunks: 0,
+            max_chunks_before_flush: None,
+            flush_interval_ms: None,
+            timeout_ms: None,
+            timeout_task: None,
+            signals: Arc::default(),
+            early_stop_on_first_done: false,
+        }
+    }
+
+    /// Stop every joined sequence in the group which is still generating with `reason`, regardless of the usual
+    /// stop conditions. The stop is deferred to each sequence's next token, rather than setting the state to
+    /// `Done` here: the scheduler drops done sequences before they produce a choice, so the group would never
+    /// complete. A `StopString` reason is replaced by `Canceled`, as its position only applies to one sequence.
+    pub fn broadcast_stop_signal(&mut self, reason: StopReason) {
+        self.signals.write().unwrap().stop_signal = Some(match reason {
+            StopReason::StopString { .. } => StopReason::Canceled,
+            reason => reason,
+        });
+    }
+
+    /// Set a deadline, relative to when `start_timeout` is called, for all choices to finish.
+    pub fn with_timeout(mut self, timeout_ms: u128) -> Self {
+        self.timeout_ms = Some(timeout_ms);
+        self
+    }
+
+    /// The signals which the sequences of this group join with [`Sequence::join_group_signals`].
+    pub fn signals(&self) -> Arc<RwLock<GroupSignals>> {
+        self.signals.clone()
+    }
+
+    /// Spawn a task which, if the choices have not all finished before the timeout, finishes the joined sequences
+    /// of the group which are still running or waiting with `StopReason::Timeout` and sends a
+    /// `Response::TimeoutError` to `responder`. The task is cancelled once the done response is sent.
+    pub fn start_timeout(&mut self, responder: Sender<Response>) {
+        let Some(timeout_ms) = self.timeout_ms else {
+            return;
+        };
+        if let Some(task) = self.timeout_task.take() {
+            task.abort();
         }
+        let signals = self.signals.clone();
+        self.timeout_task = Some(tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(
+                timeout_ms.try_into().unwrap_or(u64::MAX),
+            ))
+            .await;
+            let seq_states = signals.read().unwrap().seq_states.clone();
+            for state in seq_states.iter().filter_map(Weak::upgrade) {
+                let mut state = state.write().unwrap();
+                if !matches!(*state, SequenceState::Done(_) | SequenceState::Error) {
+                    *state = SequenceState::Done(StopReason::Timeout);
+                }
+            }
+            // The receiver may already be gone, in which case nobody is waiting for the error.
+            let _ = responder
+                .send(Response::TimeoutError(
+                    format!("Request timed out after {timeout_ms} ms.").into(),
+                ))
+                .await;
+        }));
+    }
+
+    fn cancel_timeout(&self) {
+        if let Some(task) = &self.timeout_task {
+            task.abort();
+        }
+    }
+
+    /// The target number of choices, after any decreases from errored sequences.
+    pub fn n_choices_target(&self) -> usize {
+        self.n_choices
+    }
+
+    /// The number of choices which have not finished yet.
+    pub fn n_choices_remaining(&self) -> usize {
+        self.n_choices
+            .saturating_sub(self.choices.len() + self.completion_choices.len())
     }
 
     /// This does not apply best_of.
@@ -553,7 +1354,37 @@ impl SequenceGroup {
             .collect::<Vec<_>>()
     }
 
+    /// Decode the generated tokens (excluding the prompt) of each choice, in the order they finished.
+    pub fn get_completion_texts(
+        &self,
+        tokenizer: &Tokenizer,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        self.choice_tokens
+            .iter()
+            .map(|(_, toks)| tokenizer.decode(toks, true))
+            .collect()
+    }
+
+    /// Decode the generated tokens of the choice with the highest cumulative logprob.
+    pub fn get_best_completion_text(
+        &self,
+        tokenizer: &Tokenizer,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        self.choice_tokens
+            .iter()
+            .max_by(|a, b| a.0.partial_cmp(&b.0).expect("No ordering."))
+            .map(|(_, toks)| tokenizer.decode(toks, true))
+            .transpose()
+    }
+
     pub fn get_usage(&self) -> Usage {
+        let mut warnings = Vec::new();
+        if self.input_tokens_truncated > 0 {
+            warnings.push(format!(
+                "The prompt was truncated by {} tokens to fit the model's maximum length.",
+                self.input_tokens_truncated
+            ));
+        }
         #[allow(clippy::cast_precision_loss)]
         Usage {
             completion_tokens: self.total_toks - self.total_prompt_toks,
@@ -568,30 +1399,113 @@ impl SequenceGroup {
             total_time_sec: self.total_time as f32 / 1000.,
             total_completion_time_sec: self.total_completion_time as f32 / 1000.,
             total_prompt_time_sec: self.total_prompt_time as f32 / 1000.,
+            input_tokens_truncated: self.input_tokens_truncated,
+            warnings,
         }
     }
 
+    /// Attach arbitrary caller data, returned as the `user` field of the chat completion response.
+    pub fn attach_user_context(&mut self, user_context: serde_json::Value) {
+        self.user_context = Some(user_context);
+    }
+
+    pub fn user_context(&self) -> Option<&serde_json::Value> {
+        self.user_context.as_ref()
+    }
+
+    /// The user context as the response's `user` field: strings are passed through, other values as JSON.
+    pub fn get_user(&self) -> Option<String> {
+        self.user_context.as_ref().map(|ctx| match ctx {
+            serde_json::Value::String(s) => s.clone(),
+            ctx => ctx.to_string(),
+        })
+    }
+
+    /// Estimate the memory needed to run `seqs`, the sequences of this group: their KV caches plus the
+    /// hidden states and attention scores of a forward pass over the longest of them, in the cache dtype.
+    pub fn estimated_memory_bytes(&self, seqs: &[&Sequence], config: ModelConfig) -> usize {
+        let kv_cache_bytes: usize = seqs.iter().map(|seq| seq.kv_cache_size_bytes()).sum();
+        let dtype_size = seqs
+            .iter()
+            .flat_map(|seq| seq.cache.iter().flatten())
+            .map(|(k, _)| k.dtype().size_in_bytes())
+            .next()
+            .unwrap_or(DType::F32.size_in_bytes());
+        let batch_size = seqs.len();
+        let seq_len = seqs.iter().map(|seq| seq.len()).max().unwrap_or(0);
+        let activation_bytes =
+            batch_size * seq_len * (config.hidden_size + config.n_heads * seq_len) * dtype_size;
+        kv_cache_bytes + activation_bytes
+    }
+
+    /// The total length of the sequences of this group in `seqs` which are neither done nor errored.
+    pub fn n_tokens_in_flight(&self, seqs: &[&Sequence]) -> usize {
+        seqs.iter()
+            .filter(|seq| !seq.is_finished() && !seq.is_error())
+            .map(|seq| seq.len())
+            .sum()
+    }
+
+    /// `n_tokens_in_flight`, counting the KV cache bytes of those sequences instead.
+    pub fn n_kv_cache_bytes_in_flight(&self, seqs: &[&Sequence]) -> usize {
+        seqs.iter()
+            .filter(|seq| !seq.is_finished() && !seq.is_error())
+            .map(|seq| seq.kv_cache_size_bytes())
+            .sum()
+    }
+
+    /// `get_usage` serialized to JSON.
+    pub fn get_usage_as_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&self.get_usage())
+    }
+
     pub async fn maybe_send_done_response(
         &self,
         response: ChatCompletionResponse,
         sender: Sender<Response>,
     ) -> Result<(), SendError<Response>> {
         if self.choices.len() == self.n_choices {
+            debug_assert_eq!(self.n_choices_remaining(), 0);
+            self.cancel_timeout();
             sender.send(Response::Done(response)).await?;
         }
 
         Ok(())
     }
 
+    /// Send `error_msg` once every sequence in the group has errored, so the client still gets a terminal message.
+    pub async fn maybe_send_error_response(
+        &self,
+        error_msg: String,
+        sender: Sender<Response>,
+    ) -> Result<(), SendError<Response>> {
+        if self.n_choices == 0 {
+            self.cancel_timeout();
+            sender
+                .send(Response::InternalError(error_msg.into()))
+                .await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn maybe_send_streaming_response(
         &mut self,
         seq: &Sequence,
         model: String,
     ) -> Result<(), Box<SendError<Response>>> {
-        if self.streaming_chunks.len() == self.n_choices && self.is_streaming {
-            let mut swap_streaming_chunks = vec![];
-
-            std::mem::swap(&mut swap_streaming_chunks, &mut self.streaming_chunks);
+        if self.is_streaming && self.should_flush_streaming_chunks() {
+            let swap_streaming_chunks = Vec::from(std::mem::take(&mut self.streaming_chunks));
+            for chunk in &swap_streaming_chunks {
+                if self.sent_streaming_text.len() <= chunk.index {
+                    self.sent_streaming_text
+                        .resize(chunk.index + 1, String::new());
+                    self.sent_chunk_ends.resize(chunk.index + 1, Vec::new());
+                }
+                let text = &mut self.sent_streaming_text[chunk.index];
+                text.push_str(&chunk.delta.content);
+                self.sent_chunk_ends[chunk.index].push(text.len());
+            }
 
             seq.responder()
                 .send(Response::Chunk(ChatCompletionChunkResponse {
@@ -603,18 +1517,427 @@ impl SequenceGroup {
                     object: "chat.completion.chunk".to_string(),
                 }))
                 .await?;
+
+            self.last_chunk_timestamp = Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time travel has occurred!")
+                    .as_millis(),
+            );
         }
         Ok(())
     }
 
+    /// Buffer a streaming chunk. If the buffer is full, the oldest chunk is dropped. Chunks with a
+    /// `finish_reason` are never dropped, as they end the stream.
+    pub fn push_streaming_chunk(&mut self, chunk: ChunkChoice) {
+        if self
+            .max_chunks_buffered
+            .is_some_and(|max| self.streaming_chunks.len() >= max)
+        {
+            let oldest = self
+                .streaming_chunks
+                .iter()
+                .position(|chunk| chunk.finish_reason.is_none());
+            if let Some(i) = oldest {
+                self.streaming_chunks.remove(i);
+                self.dropped_chunks += 1;
+            }
+        }
+        self.streaming_chunks.push_back(chunk);
+    }
+
+    pub fn get_chunk_at(&self, index: usize) -> Option<&ChunkChoice> {
+        self.streaming_chunks.get(index)
+    }
+
+    /// The text of every streaming chunk of the choice with index `choice` so far, sent or still buffered, in
+    /// order. Chunks dropped because the buffer was full are not included. No tokenizer is needed, as the
+    /// chunks already hold the decoded text of their tokens.
+    pub fn get_streaming_text(&self, choice: usize) -> String {
+        self.get_streaming_text_up_to_chunk(choice, usize::MAX)
+    }
+
+    /// `get_streaming_text` for only the first `n` chunks of the choice.
+    pub fn get_streaming_text_up_to_chunk(&self, choice: usize, n: usize) -> String {
+        let sent = self
+            .sent_streaming_text
+            .get(choice)
+            .map_or("", String::as_str);
+        let ends = self
+            .sent_chunk_ends
+            .get(choice)
+            .map_or(&[][..], Vec::as_slice);
+        if n <= ends.len() {
+            let end = n.checked_sub(1).map_or(0, |i| ends[i]);
+            return sent[..end].to_string();
+        }
+        let mut text = sent.to_string();
+        for chunk in self
+            .streaming_chunks
+            .iter()
+            .filter(|chunk| chunk.index == choice)
+            .take(n - ends.len())
+        {
+            text.push_str(&chunk.delta.content);
+        }
+        text
+    }
+
+    /// Limit the number of buffered streaming chunks. `None` means unbounded. The limit must be at least
+    /// the number of choices, or chunks would be dropped before every choice has one to send.
+    pub fn set_max_chunks_buffered(
+        &mut self,
+        max_chunks_buffered: Option<usize>,
+    ) -> candle_core::Result<()> {
+        if let Some(max) = max_chunks_buffered.filter(|max| *max < self.n_choices.max(1)) {
+            candle_core::bail!(
+                "max_chunks_buffered is {max}, but must be at least the number of choices ({}).",
+                self.n_choices.max(1)
+            );
+        }
+        self.max_chunks_buffered = max_chunks_buffered;
+        Ok(())
+    }
+
+    /// The number of streaming chunks dropped because the buffer was full.
+    pub fn dropped_chunks(&self) -> usize {
+        self.dropped_chunks
+    }
+
+    /// Send the buffered chunks once there are `max_chunks` of them, even if not every choice has one or
+    /// streaming is paused. `max_chunks` must be at least 1.
+    pub fn set_max_chunks_before_flush(
+        &mut self,
+        max_chunks: Option<usize>,
+    ) -> candle_core::Result<()> {
+        if max_chunks == Some(0) {
+            candle_core::bail!("max_chunks_before_flush must be at least 1.");
+        }
+        self.max_chunks_before_flush = max_chunks;
+        Ok(())
+    }
+
+    /// Send the buffered chunks once `interval_ms` has passed since the last chunk was sent, even if not
+    /// every choice has one.
+    pub fn set_flush_interval_ms(&mut self, interval_ms: Option<u128>) {
+        self.flush_interval_ms = interval_ms;
+    }
+
+    /// Stop sending streaming chunks, for backpressure. They are buffered instead, up to the
+    /// `set_max_chunks_buffered` limit. The final chunks of the stream are always sent, and so is the buffer once
+    /// it reaches the `set_max_chunks_before_flush` limit.
+    pub fn pause_streaming(&mut self) {
+        self.streaming_paused = true;
+    }
+
+    /// Resume sending streaming chunks. The buffered chunks are sent with the next chunk.
+    pub fn resume_streaming(&mut self) {
+        self.streaming_paused = false;
+    }
+
+    pub fn is_streaming_paused(&self) -> bool {
+        self.streaming_paused
+    }
+
+    fn should_flush_streaming_chunks(&self) -> bool {
+        let n_chunks = self.streaming_chunks.len();
+        if n_chunks == 0 {
+            return false;
+        }
+        // Otherwise a stream which finishes while paused, or before every choice has a chunk, would never end.
+        if self
+            .streaming_chunks
+            .iter()
+            .any(|chunk| chunk.finish_reason.is_some())
+        {
+            return true;
+        }
+        // Bounds the buffer, so this applies while paused too.
+        if self
+            .max_chunks_before_flush
+            .is_some_and(|max| n_chunks >= max)
+        {
+            return true;
+        }
+        if self.streaming_paused {
+            return false;
+        }
+        // `>=` rather than `==`: n_choices decreases when a choice errors, which may leave extra chunks.
+        n_chunks >= self.n_choices
+            || self
+                .flush_interval_ms
+                .is_some_and(|interval| self.is_stalled(interval))
+    }
+
+    /// Time of the last streaming chunk sent, in ms since the Unix epoch.
+    pub fn last_chunk_timestamp(&self) -> Option<u128> {
+        self.last_chunk_timestamp
+    }
+
+    /// Milliseconds since the last streaming chunk was sent, or `None` if none have been sent.
+    pub fn ms_since_last_chunk(&self) -> Option<u128> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time travel has occurred!")
+            .as_millis();
+        self.last_chunk_timestamp
+            .map(|last| now.saturating_sub(last))
+    }
+
+    /// If a chunk has been sent but none for more than `timeout_ms`.
+    pub fn is_stalled(&self, timeout_ms: u128) -> bool {
+        self.ms_since_last_chunk()
+            .is_some_and(|elapsed| elapsed > timeout_ms)
+    }
+
     pub async fn maybe_send_completion_done_response(
         &self,
         response: CompletionResponse,
         sender: Sender<Response>,
     ) -> Result<(), Box<SendError<Response>>> {
         if self.completion_choices.len() == self.n_choices {
+            debug_assert_eq!(self.n_choices_remaining(), 0);
+            self.cancel_timeout();
             sender.send(Response::CompletionDone(response)).await?;
         }
         Ok(())
     }
 }
+
+impl Drop for SequenceGroup {
+    fn drop(&mut self) {
+        self.cancel_timeout();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use candle_core::Device;
+    use tokenizers::models::wordlevel::WordLevel;
+
+    pub(crate) fn new_sequence(tokens: Vec<u32>) -> Sequence {
+        let (responder, _) = tokio::sync::mpsc::channel(1);
+        let tokenizer = Arc::new(Tokenizer::new(WordLevel::default()));
+        let sampler = Sampler::new(None, 0, tokenizer, None, None, None, -1, 1.0);
+        let group = SequenceGroup::new(1, false, false, 1);
+        let signals = group.signals();
+        let mut seq = Sequence::new_waiting(
+            tokens,
+            0,
+            0,
+            1,
+            responder,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            false,
+            false,
+            Arc::new(Mutex::new(group)),
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+        );
+        seq.join_group_signals(signals);
+        seq
+    }
+
+    fn logprobs(token: u32) -> Logprobs {
+        Logprobs {
+            token,
+            logprob: 0.,
+            bytes: String::new(),
+            top_logprobs: None,
+        }
+    }
+
+    #[test]
+    fn forced_tokens_follow_generation_start_token() {
+        let mut seq = new_sequence(vec![1, 2]);
+        seq.set_generation_start_token(Some(7));
+        seq.set_forced_tokens(Some(vec![3, 4]));
+
+        let mut generated = Vec::new();
+        while let Some(tok) = seq.next_forced_token() {
+            let is_done = seq.is_done(tok, 0., None, usize::MAX);
+            seq.add_token(logprobs(tok), Vec::new(), &is_done);
+            generated.push(tok);
+            if is_done.is_some() {
+                assert_eq!(is_done, Some(StopReason::ForcedTokensExhausted));
+                break;
+            }
+        }
+        assert_eq!(generated, vec![7, 3, 4]);
+    }
+
+    #[test]
+    fn prompt_hash_is_fixed() {
+        assert_eq!(
+            new_sequence(vec![1, 2, 3]).prompt_hash(),
+            0xb5148cb100a911fc
+        );
+        assert_ne!(
+            new_sequence(vec![1, 2]).prompt_hash(),
+            new_sequence(vec![2, 1]).prompt_hash()
+        );
+    }
+
+    #[test]
+    fn block_allocation_rejects_bad_input() {
+        let mut seq = new_sequence(vec![1]);
+        assert!(seq.set_block_size(0).is_err());
+        assert!(seq.allocate_block(1).is_err());
+        assert!(seq.free_block(1, 0).is_err());
+
+        assert_eq!(seq.allocate_block(0).unwrap(), 0);
+        assert_eq!(seq.allocate_block(0).unwrap(), 1);
+        seq.free_block(0, 0).unwrap();
+        assert_eq!(seq.allocate_block(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn chunk_buffer_keeps_finished_chunks() {
+        let chunk = |content: &str, finish_reason: Option<&str>| ChunkChoice {
+            finish_reason: finish_reason.map(str::to_string),
+            index: 0,
+            delta: crate::response::Delta {
+                content: content.to_string(),
+                role: "assistant".to_string(),
+            },
+            logprobs: None,
+        };
+        let mut group = SequenceGroup::new(2, true, true, 2);
+        assert!(group.set_max_chunks_buffered(Some(0)).is_err());
+        assert!(group.set_max_chunks_buffered(Some(1)).is_err());
+        group.set_max_chunks_buffered(Some(2)).unwrap();
+
+        group.push_streaming_chunk(chunk("", Some("stop")));
+        for content in ["a", "b", "c"] {
+            group.push_streaming_chunk(chunk(content, None));
+        }
+        assert_eq!(group.dropped_chunks(), 2);
+        assert_eq!(
+            group.get_chunk_at(0).unwrap().finish_reason.as_deref(),
+            Some("stop")
+        );
+        assert_eq!(group.get_chunk_at(1).unwrap().delta.content, "c");
+    }
+
+    #[test]
+    fn streaming_text_is_per_choice() {
+        let chunk = |index: usize, content: &str| ChunkChoice {
+            finish_reason: None,
+            index,
+            delta: crate::response::Delta {
+                content: content.to_string(),
+                role: "assistant".to_string(),
+            },
+            logprobs: None,
+        };
+        let mut group = SequenceGroup::new(2, true, true, 2);
+        for (index, content) in [(0, "a"), (1, "x"), (0, "b"), (1, "y"), (0, "c")] {
+            group.push_streaming_chunk(chunk(index, content));
+        }
+        assert_eq!(group.get_streaming_text(0), "abc");
+        assert_eq!(group.get_streaming_text(1), "xy");
+        assert_eq!(group.get_streaming_text_up_to_chunk(0, 2), "ab");
+        assert_eq!(group.get_streaming_text_up_to_chunk(1, 0), "");
+        assert_eq!(group.get_streaming_text(2), "");
+    }
+
+    #[test]
+    fn no_repeat_ngram_size_stops_greedy_loop() {
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+
+        // `(t + 1) % 3` is always the most likely token after `t`, so plain greedy decoding loops forever.
+        let decode = |no_repeat_ngram_size: Option<usize>| {
+            let rng = Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(0)));
+            let mut seq = new_sequence(vec![0]);
+            seq.set_no_repeat_ngram_size(no_repeat_ngram_size);
+            for _ in 0..20 {
+                let last = *seq.get_toks().last().unwrap();
+                let mut logits: Vec<f32> = (0..8).map(|t| -(t as f32)).collect();
+                logits[((last + 1) % 3) as usize] = 1.;
+                let logits = Tensor::from_vec(logits, 8, &Device::Cpu).unwrap();
+                let next = seq
+                    .sampler()
+                    .sample(logits, None, false, rng.clone(), false)
+                    .unwrap();
+                seq.add_token(next, Vec::new(), &None);
+            }
+            seq.get_toks().to_vec()
+        };
+        let n_unique_trigrams = |toks: &[u32]| {
+            toks.windows(3)
+                .collect::<std::collections::HashSet<_>>()
+                .len()
+        };
+
+        let looping = decode(None);
+        assert_eq!(&looping[..6], &[0, 1, 2, 0, 1, 2]);
+        assert_eq!(n_unique_trigrams(&looping), 3);
+
+        let blocked = decode(Some(3));
+        assert_eq!(blocked.len(), 21);
+        assert_eq!(n_unique_trigrams(&blocked), blocked.len() - 2);
+    }
+
+    #[test]
+    fn broadcast_stop_signal_stops_at_next_token() {
+        let seq = new_sequence(vec![1, 2]);
+        assert_eq!(seq.is_done(3, 0., None, 100), None);
+        get_mut_group!(seq).broadcast_stop_signal(StopReason::Canceled);
+        assert!(matches!(*seq.state.read().unwrap(), SequenceState::Waiting));
+        assert_eq!(seq.is_done(3, 0., None, 100), Some(StopReason::Canceled));
+    }
+
+    #[test]
+    fn restore_undoes_generation_since_snapshot() {
+        let kv = |len| Tensor::zeros((1, 1, len, 2), DType::F32, &Device::Cpu).unwrap();
+        let mut seq = new_sequence(vec![1, 2]);
+        seq.add_token(logprobs(3), b"a".to_vec(), &None);
+        seq.cache()[0] = Some((kv(3), kv(3)));
+        let snap = seq.snapshot().unwrap();
+
+        for tok in [4, 5] {
+            let tok = Logprobs {
+                logprob: -1.,
+                ..logprobs(tok)
+            };
+            seq.add_token(tok, b"b".to_vec(), &None);
+        }
+        seq.cache()[0] = Some((kv(5), kv(5)));
+        seq.restore(&snap).unwrap();
+
+        assert_eq!(seq.get_toks(), &[1, 2, 3]);
+        assert_eq!(seq.logprobs().len(), 1);
+        assert_eq!(seq.completion_bytes(), b"a");
+        assert_eq!(seq.cumulative_logprob, 0.);
+        let (k, v) = seq.cache()[0].as_ref().unwrap();
+        assert_eq!((k.dim(2).unwrap(), v.dim(2).unwrap()), (3, 3));
+    }
+
+    #[test]
+    fn split_at_token_keeps_sampling_params() {
+        let mut seq = new_sequence(vec![1, 2, 3]);
+        seq.set_temperature_schedule(Some(linear_annealing_schedule(1.0, 0.5, 10)));
+        seq.set_forced_tokens(Some(vec![4]));
+        seq.set_generation_start_token(Some(5));
+        seq.set_logprob_threshold(Some(-2.0));
+
+        let (responder, _) = tokio::sync::mpsc::channel(1);
+        let (_, suffix) = seq.split_at_token(2, 1, responder).unwrap();
+        let schedule = suffix.temperature_schedule.as_ref().unwrap();
+        assert_eq!(schedule(10), 0.5);
+        assert_eq!(suffix.forced_tokens(), Some(&[4][..]));
+        assert_eq!(suffix.generation_start_token(), Some(5));
+        assert_eq!(suffix.logprob_threshold(), Some(-2.0));
+    }
+}
@@ -0,0 +1,145 @@
+#![allow(clippy::cast_precision_loss)]
+
+//! Numerically stable softmax, log-softmax, entropy and top-k helpers.
+//!
+//! The slice functions are the reference implementation. The tensor functions operate over the
+//! last dimension and always compute in F32, so F16/BF16 logits produce the same results as F32 ones.
+//! Entropy is in nats.
+
+use candle_core::{DType, Result, Tensor, D};
+
+/// Softmax of `logits`. If every logit is `-inf`, the result is all zeros.
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return vec![0.0; logits.len()];
+    }
+    let exps = logits.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f32>();
+    exps.into_iter().map(|x| x / sum).collect()
+}
+
+/// Log-softmax of `logits`. If every logit is `-inf`, the result is all `-inf`.
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return vec![f32::NEG_INFINITY; logits.len()];
+    }
+    let log_sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|x| x - log_sum).collect()
+}
+
+/// If no entry can be sampled: every logit is `-inf` or NaN. Softmax of such a row is NaN.
+pub fn is_all_masked(logits: &[f32]) -> bool {
+    logits.iter().all(|x| *x == f32::NEG_INFINITY || x.is_nan())
+}
+
+/// Entropy of the distribution given by `logits`. Masked (`-inf`) logits contribute nothing.
+pub fn entropy(logits: &[f32]) -> f32 {
+    log_softmax(logits)
+        .into_iter()
+        .filter(|logp| logp.is_finite())
+        .map(|logp| -logp.exp() * logp)
+        .sum()
+}
+
+/// Indices of `values` sorted by descending value.
+pub fn argsort_descending(values: &[f32]) -> Vec<usize> {
+    let mut indices = (0..values.len()).collect::<Vec<_>>();
+    indices.sort_unstable_by(|&i, &j| values[j].partial_cmp(&values[i]).expect("No ordering."));
+    indices
+}
+
+/// Indices of the `k` largest values, largest first. Returns fewer than `k` if `values` is shorter.
+pub fn top_k_indices(values: &[f32], k: usize) -> Vec<usize> {
+    let mut indices = argsort_descending(values);
+    indices.truncate(k);
+    indices
+}
+
+/// Softmax over the last dimension.
+pub fn softmax_last_dim(logits: &Tensor) -> Result<Tensor> {
+    candle_nn::ops::softmax_last_dim(&logits.to_dtype(DType::F32)?)
+}
+
+/// Log-softmax over the last dimension.
+pub fn log_softmax_last_dim(logits: &Tensor) -> Result<Tensor> {
+    candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)
+}
+
+/// Entropy over the last dimension. The last dimension is removed.
+pub fn entropy_last_dim(logits: &Tensor) -> Result<Tensor> {
+    let log_probs = log_softmax_last_dim(logits)?;
+    let probs = log_probs.exp()?;
+    // 0 * -inf is NaN, so zero out the masked entries explicitly.
+    let plogp = probs
+        .eq(0.0)?
+        .where_cond(&probs.zeros_like()?, &(&probs * &log_probs)?)?;
+    plogp.sum(D::Minus1)?.neg()
+}
+
+/// Indices of the `k` largest values of a 1D tensor, largest first.
+pub fn top_k_indices_1d(values: &Tensor, k: usize) -> Result<Vec<usize>> {
+    let values = values.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    Ok(top_k_indices(&values, k))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    const LOGITS: [f32; 4] = [1.0, 2.0, 3.0, f32::NEG_INFINITY];
+
+    #[test]
+    fn test_softmax_sums_to_one() {
+        let probs = softmax(&LOGITS);
+        assert!((probs.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert_eq!(probs[3], 0.0);
+        assert!(probs[2] > probs[1] && probs[1] > probs[0]);
+    }
+
+    #[test]
+    fn test_log_softmax_matches_softmax() {
+        for (logp, p) in log_softmax(&LOGITS).into_iter().zip(softmax(&LOGITS)) {
+            assert!((logp.exp() - p).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_all_masked() {
+        let masked = [f32::NEG_INFINITY; 3];
+        assert_eq!(softmax(&masked), vec![0.0; 3]);
+        assert_eq!(entropy(&masked), 0.0);
+        assert!(is_all_masked(&masked));
+        assert!(is_all_masked(&[f32::NAN, f32::NEG_INFINITY]));
+        assert!(!is_all_masked(&LOGITS));
+    }
+
+    #[test]
+    fn test_entropy_uniform() {
+        let h = entropy(&[0.5; 8]);
+        assert!((h - 8f32.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_top_k_indices() {
+        assert_eq!(top_k_indices(&LOGITS, 2), vec![2, 1]);
+        assert_eq!(top_k_indices(&LOGITS, 10).len(), 4);
+    }
+
+    #[test]
+    fn test_tensor_matches_slice() {
+        let t = Tensor::new(&LOGITS, &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::F16)
+            .unwrap();
+        let probs = softmax_last_dim(&t).unwrap().to_vec1::<f32>().unwrap();
+        for (a, b) in probs.into_iter().zip(softmax(&LOGITS)) {
+            assert!((a - b).abs() < 1e-3);
+        }
+        let h = entropy_last_dim(&t).unwrap().to_scalar::<f32>().unwrap();
+        assert!((h - entropy(&LOGITS)).abs() < 1e-3);
+        assert_eq!(top_k_indices_1d(&t, 2).unwrap(), vec![2, 1]);
+    }
+}
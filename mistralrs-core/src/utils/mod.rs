@@ -1,4 +1,5 @@
 pub(crate) mod debug;
+pub(crate) mod distribution;
 pub(crate) mod gguf_metadata;
 pub(crate) mod model_config;
 pub(crate) mod normal;
@@ -136,6 +137,7 @@ macro_rules! handle_pipeline_forward_error {
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
+                            user: group.get_user(),
                         };
 
                         seq.responder()
@@ -168,6 +170,12 @@ macro_rules! handle_pipeline_forward_error {
                 for seq in $seq_slice.iter_mut() {
                     // Step 3: Set state - This cannot be done in Step 2 as `group` is locking the refcell
                     seq.set_state(SequenceState::Error);
+                    // Once every choice has errored, send the terminal error, which also cancels the group timeout.
+                    // The receiver may already be gone after the model error response.
+                    let _ = seq
+                        .get_mut_group()
+                        .maybe_send_error_response(e.to_string(), seq.responder())
+                        .await;
                 }
 
                 let mut p = get_mut_arcmutex!($pipeline);
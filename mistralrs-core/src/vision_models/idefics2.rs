@@ -1043,6 +1043,7 @@ impl Idefics2 {
         self.text_model.forward_embeds(
             input_ids,
             input_embeds,
+            None,
             seqlen_offsets,
             start_offsets_kernel,
             context_lens,
@@ -123,12 +123,20 @@ impl InputsProcessor for Idefics2ImageProcessor {
         if no_kv_cache {
             anyhow::bail!("Vision model must have kv cache.");
         }
+        // Image tokens are spliced into the prompt, so the prompt masks would no longer line up.
+        if input_seqs
+            .iter()
+            .any(|seq| seq.prompt_attention_mask().is_some())
+        {
+            anyhow::bail!("Prompt attention masks are not supported for vision models.");
+        }
         let text_models_inputs_processor::InputMetadata {
             input,
             positions,
             positions_kernel,
             context_lens,
             position_ids,
+            attention_mask: _,
         } = if is_prompt {
             get_prompt_input(
                 input_seqs
@@ -81,6 +81,13 @@ impl InputsProcessor for Phi3InputsProcessor {
         if no_kv_cache {
             anyhow::bail!("Vision model must have kv cache.");
         }
+        // Image tokens are spliced into the prompt, so the prompt masks would no longer line up.
+        if input_seqs
+            .iter()
+            .any(|seq| seq.prompt_attention_mask().is_some())
+        {
+            anyhow::bail!("Prompt attention masks are not supported for vision models.");
+        }
 
         let config = other_config
             .clone()
@@ -129,6 +136,7 @@ impl InputsProcessor for Phi3InputsProcessor {
                 seqlen_offsets_kernel_full: _,
                 context_lens,
                 position_ids,
+                attention_mask: _,
             } = *text_models_inputs_processor::TextInputsProcessor
                 .process_inputs(
                     tokenizer,
@@ -249,6 +257,7 @@ impl InputsProcessor for Phi3InputsProcessor {
             positions_kernel,
             context_lens,
             position_ids,
+            attention_mask: _,
         } = if is_prompt {
             get_prompt_input(toks, input_seqs, device, last_n_context_len)?
         } else {
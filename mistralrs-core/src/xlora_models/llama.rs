@@ -686,6 +686,7 @@ impl NormalModel for XLoraLlama {
     fn forward(
         &mut self,
         _input_ids: &Tensor,
+        _padding_mask: Option<&Tensor>,
         _seqlen_offsets: &[usize],
         _start_offsets_kernel: Tensor,
         _context_lens: Vec<(usize, usize)>,
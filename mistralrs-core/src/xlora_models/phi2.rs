@@ -680,6 +680,7 @@ impl NormalModel for Model {
     fn forward(
         &mut self,
         _input_ids: &Tensor,
+        _padding_mask: Option<&Tensor>,
         _seqlen_offsets: &[usize],
         _start_offsets_kernel: Tensor,
         _context_lens: Vec<(usize, usize)>,
@@ -30,6 +30,7 @@ class ChatCompletionRequest:
     grammar: str | None = None
     grammar_type: str | None = None
     adapters: list[str] | None = None
+    user: str | None = None
 
 @dataclass
 class CompletionRequest:
@@ -225,6 +226,8 @@ class Usage:
     total_time_sec: float
     total_prompt_time_sec: float
     total_completion_time_sec: float
+    input_tokens_truncated: int
+    warnings: list[str]
 
 @dataclass
 class ResponseMessage:
@@ -264,6 +267,7 @@ class ChatCompletionResponse:
     system_fingerprint: str
     object: str
     usage: Usage
+    user: str | None
 
 @dataclass
 class Delta:
@@ -26,7 +26,7 @@ use mistralrs_core::{
     VisionLoaderBuilder, VisionSpecificConfig,
 };
 use pyo3::{
-    exceptions::{PyTypeError, PyValueError},
+    exceptions::{PyTimeoutError, PyTypeError, PyValueError},
     prelude::*,
     types::{PyList, PyString},
 };
@@ -672,6 +672,8 @@ impl Runner {
                 constraint,
                 suffix: None,
                 adapters: request.adapters.clone(),
+                timeout_ms: None,
+                user: request.user.clone(),
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -687,6 +689,7 @@ impl Runner {
                     Response::ValidationError(e) | Response::InternalError(e) => {
                         Err(PyValueError::new_err(e.to_string()))
                     }
+                    Response::TimeoutError(e) => Err(PyTimeoutError::new_err(e.to_string())),
                     Response::Done(response) => Ok(Either::Left(response)),
                     Response::ModelError(msg, _) => Err(PyValueError::new_err(msg.to_string())),
                     Response::Chunk(_) => unreachable!(),
@@ -761,6 +764,8 @@ impl Runner {
                 constraint,
                 suffix: request.suffix.clone(),
                 adapters: request.adapters.clone(),
+                timeout_ms: None,
+                user: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -772,6 +777,7 @@ impl Runner {
                 Response::ValidationError(e) | Response::InternalError(e) => {
                     Err(PyValueError::new_err(e.to_string()))
                 }
+                Response::TimeoutError(e) => Err(PyTimeoutError::new_err(e.to_string())),
                 Response::CompletionDone(response) => Ok(response),
                 Response::CompletionModelError(msg, _) => {
                     Err(PyValueError::new_err(msg.to_string()))
@@ -919,6 +925,7 @@ struct ChatCompletionRequest {
     grammar: Option<String>,
     grammar_type: Option<String>,
     adapters: Option<Vec<String>>,
+    user: Option<String>,
 }
 
 #[pymethods]
@@ -941,7 +948,8 @@ impl ChatCompletionRequest {
         stream=false,
         grammar = None,
         grammar_type = None,
-        adapters = None
+        adapters = None,
+        user = None
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -961,6 +969,7 @@ impl ChatCompletionRequest {
         grammar: Option<String>,
         grammar_type: Option<String>,
         adapters: Option<Vec<String>>,
+        user: Option<String>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -1028,6 +1037,7 @@ impl ChatCompletionRequest {
             grammar,
             grammar_type,
             adapters,
+            user,
         })
     }
 }
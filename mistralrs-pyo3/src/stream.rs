@@ -1,7 +1,10 @@
 use tokio::sync::mpsc::Receiver;
 
 use mistralrs_core::{ChatCompletionChunkResponse, Response};
-use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyRef, PyRefMut, PyResult};
+use pyo3::{
+    exceptions::{PyTimeoutError, PyValueError},
+    pyclass, pymethods, PyRef, PyRefMut, PyResult,
+};
 
 #[pyclass]
 pub struct ChatCompletionStreamer {
@@ -29,6 +32,10 @@ impl ChatCompletionStreamer {
                 Response::ModelError(msg, _) => Some(Err(PyValueError::new_err(msg.to_string()))),
                 Response::ValidationError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
                 Response::InternalError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
+                Response::TimeoutError(e) => {
+                    this.is_done = true;
+                    Some(Err(PyTimeoutError::new_err(e.to_string())))
+                }
                 Response::Chunk(response) => {
                     if response.choices.iter().all(|x| x.finish_reason.is_some()) {
                         this.is_done = true;
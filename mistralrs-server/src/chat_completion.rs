@@ -64,6 +64,10 @@ impl futures::Stream for Streamer {
                 Response::ValidationError(e) => {
                     Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
                 }
+                Response::TimeoutError(e) => {
+                    self.is_done = true;
+                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                }
                 Response::InternalError(e) => {
                     MistralRs::maybe_log_error(self.state.clone(), &*e);
                     Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
@@ -90,6 +94,7 @@ pub enum ChatCompletionResponder {
     ModelError(String, ChatCompletionResponse),
     InternalError(Box<dyn Error>),
     ValidationError(Box<dyn Error>),
+    TimeoutError(Box<dyn Error>),
 }
 
 trait ErrorToResponse: Serialize {
@@ -140,6 +145,9 @@ impl IntoResponse for ChatCompletionResponder {
             ChatCompletionResponder::ValidationError(e) => {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
             }
+            ChatCompletionResponder::TimeoutError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::REQUEST_TIMEOUT)
+            }
             ChatCompletionResponder::ModelError(msg, response) => {
                 JsonModelError::new(msg, response)
                     .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
@@ -319,6 +327,8 @@ async fn parse_request(
                 None => Constraint::None,
             },
             adapters: oairequest.adapters,
+            timeout_ms: oairequest.timeout_ms.map(u128::from),
+            user: oairequest.user,
         }),
         is_streaming,
     ))
@@ -391,6 +401,7 @@ pub async fn chatcompletions(
                 ChatCompletionResponder::ModelError(msg, response)
             }
             Response::ValidationError(e) => ChatCompletionResponder::ValidationError(e),
+            Response::TimeoutError(e) => ChatCompletionResponder::TimeoutError(e),
             Response::Done(response) => {
                 MistralRs::maybe_log_response(state, &response);
                 ChatCompletionResponder::Json(response)
@@ -27,6 +27,7 @@ pub enum CompletionResponder {
     ModelError(String, CompletionResponse),
     InternalError(Box<dyn Error>),
     ValidationError(Box<dyn Error>),
+    TimeoutError(Box<dyn Error>),
 }
 
 trait ErrorToResponse: Serialize {
@@ -76,6 +77,9 @@ impl IntoResponse for CompletionResponder {
             CompletionResponder::ValidationError(e) => {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
             }
+            CompletionResponder::TimeoutError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::REQUEST_TIMEOUT)
+            }
             CompletionResponder::ModelError(msg, response) => JsonModelError::new(msg, response)
                 .to_response(http::StatusCode::INTERNAL_SERVER_ERROR),
         }
@@ -133,6 +137,8 @@ fn parse_request(
             None => Constraint::None,
         },
         adapters: oairequest.adapters,
+        timeout_ms: oairequest.timeout_ms.map(u128::from),
+        user: None,
     })
 }
 
@@ -188,6 +194,7 @@ pub async fn completions(
             CompletionResponder::ModelError(msg, response)
         }
         Response::ValidationError(e) => CompletionResponder::ValidationError(e),
+        Response::TimeoutError(e) => CompletionResponder::TimeoutError(e),
         Response::CompletionDone(response) => {
             MistralRs::maybe_log_response(state, &response);
             CompletionResponder::Json(response)
@@ -80,6 +80,8 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
             constraint: Constraint::None,
             suffix: None,
             adapters: None,
+            timeout_ms: None,
+            user: None,
         });
         sender.send(req).await.unwrap();
 
@@ -111,6 +113,10 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
                     error!("Got a validation error: {e:?}");
                     break 'outer;
                 }
+                Response::TimeoutError(e) => {
+                    error!("Got a timeout error: {e:?}");
+                    break 'outer;
+                }
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
@@ -92,6 +92,8 @@ pub struct ChatCompletionRequest {
     pub top_p: Option<f64>,
     #[schema(example = true)]
     pub stream: Option<bool>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
@@ -100,6 +102,8 @@ pub struct ChatCompletionRequest {
     pub grammar: Option<Grammar>,
     #[schema(example = json!(Option::None::<Vec<String>>))]
     pub adapters: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -164,4 +168,6 @@ pub struct CompletionRequest {
     pub grammar: Option<Grammar>,
     #[schema(example = json!(Option::None::<Vec<String>>))]
     pub adapters: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub timeout_ms: Option<u64>,
 }
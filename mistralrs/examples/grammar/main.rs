@@ -51,6 +51,8 @@ fn main() -> anyhow::Result<()> {
         constraint: Constraint::Regex("(- [^\n]*\n)+(- [^\n]*)(\n\n)?".to_string()), // Bullet list regex
         suffix: None,
         adapters: None,
+        timeout_ms: None,
+        user: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
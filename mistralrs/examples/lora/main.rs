@@ -58,6 +58,8 @@ fn main() -> anyhow::Result<()> {
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        timeout_ms: None,
+        user: None,
     });
 
     // Example: Make adapter_3 the active adapter
@@ -58,6 +58,8 @@ fn main() -> anyhow::Result<()> {
         constraint: Constraint::None,
         suffix: None,
         adapters: Some(vec!["adapter_2".to_string()]),
+        timeout_ms: None,
+        user: None,
     });
 
     mistralrs.get_sender()?.blocking_send(request)?;
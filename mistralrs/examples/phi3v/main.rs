@@ -59,6 +59,8 @@ fn main() -> anyhow::Result<()> {
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        timeout_ms: None,
+        user: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -34,6 +34,8 @@
 //!         constraint: Constraint::None,
 //!         suffix: None,
 //!         adapters: None,
+//!         timeout_ms: None,
+//!         user: None,
 //!     });
 //!     mistralrs.get_sender()?.blocking_send(request)?;
 //!